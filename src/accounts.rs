@@ -0,0 +1,1300 @@
+use crate::error::StakingError;
+use crate::pda_helper::PdaHelper;
+use crate::state::{StakingState, UserStakingState, GlobalConfig};
+use solana_program::{
+    account_info::{AccountInfo, next_account_info},
+    bpf_loader_upgradeable,
+    msg,
+    program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_program,
+};
+use std::slice::Iter;
+use spl_token::state::{Account, Mint};
+
+/// Validated accounts of `StakingInstruction::Init`
+pub struct InitAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub staking_token_mint_ai: &'a AccountInfo<'b>,
+    pub rewards_token_mint_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub system_program_ai: &'a AccountInfo<'b>,
+    pub metadata_bump: u8,
+}
+
+impl<'a, 'b> InitAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let staking_token_mint_ai = next_account_info(accounts_iter)?;
+        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let system_program_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+
+        check_signer(admin_ai)?;
+        check_system_program(system_program_ai)?;
+        check_token_program(token_program_ai)?;
+
+        let (metadata_pda, metadata_bump) = PdaHelper::find_metadata_pda(staking_token_mint_ai.key, rewards_token_mint_ai.key, admin_ai.key, program_id);
+        check_pda(metadata_ai, &metadata_pda)?;
+
+        if !metadata_ai.try_data_is_empty()? {
+            msg!("Staking pool {} is already initialized", metadata_ai.key);
+            return Err(StakingError::AlreadyInitialized.into());
+        }
+
+        let (escrow_staking_token_owner, _nonce) = PdaHelper::find_staking_token_pda(metadata_ai.key, program_id);
+        check_escrow_token_account(escrow_staking_token_ai, staking_token_mint_ai.key, &escrow_staking_token_owner)?;
+
+        let (escrow_rewards_token_owner, _nonce) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+        check_escrow_token_account(escrow_rewards_token_ai, rewards_token_mint_ai.key, &escrow_rewards_token_owner)?;
+
+        Ok(InitAccounts {
+            admin_ai,
+            metadata_ai,
+            staking_token_mint_ai,
+            rewards_token_mint_ai,
+            escrow_staking_token_ai,
+            escrow_rewards_token_ai,
+            system_program_ai,
+            metadata_bump,
+        })
+    }
+}
+
+/// Receipt accounts appended to instructions which move stake of a pool with a receipt mint
+#[derive(Clone, Copy)]
+pub struct ReceiptAccounts<'a, 'b> {
+    pub receipt_mint_ai: &'a AccountInfo<'b>,
+    pub user_receipt_token_ai: &'a AccountInfo<'b>,
+    pub receipt_authority_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub receipt_authority_bump: u8,
+}
+
+/// Validated accounts of `StakingInstruction::Stake`
+pub struct StakeAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_staking_token_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub system_program_ai: &'a AccountInfo<'b>,
+    pub user_state_bump: u8,
+    /// Owner of the credited position, the signer unless staking on behalf of another wallet
+    pub beneficiary: Pubkey,
+    pub receipt: Option<ReceiptAccounts<'a, 'b>>,
+}
+
+impl<'a, 'b> StakeAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], beneficiary: Option<Pubkey>) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_staking_token_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let staking_token_mint_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+        let system_program_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        check_token_program(token_program_ai)?;
+        check_system_program(system_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_mint(staking_token_mint_ai, &state.staking_token_mint)?;
+        check_escrow(escrow_staking_token_ai, &state.staking_escrow)?;
+
+        let beneficiary = beneficiary.unwrap_or(*user_ai.key);
+        let receipt = next_receipt_accounts(program_id, accounts_iter, metadata_ai, &state, &beneficiary)?;
+        let user_state_bump = check_user_state(program_id, metadata_ai, &beneficiary, user_state_ai, true)?;
+
+        Ok(StakeAccounts {
+            user_ai,
+            user_staking_token_ai,
+            escrow_staking_token_ai,
+            user_state_ai,
+            metadata_ai,
+            token_program_ai,
+            system_program_ai,
+            user_state_bump,
+            beneficiary,
+            receipt,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::Unstake`
+pub struct UnstakeAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_staking_token_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_owner_ai: &'a AccountInfo<'b>,
+    pub staking_token_mint_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_owner_bump: u8,
+    pub receipt: Option<ReceiptAccounts<'a, 'b>>,
+    pub penalty_destination_ai: Option<&'a AccountInfo<'b>>,
+}
+
+impl<'a, 'b> UnstakeAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_staking_token_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_owner_ai = next_account_info(accounts_iter)?;
+        let staking_token_mint_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_mint(staking_token_mint_ai, &state.staking_token_mint)?;
+        check_escrow(escrow_staking_token_ai, &state.staking_escrow)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+
+        let receipt = next_receipt_accounts(program_id, accounts_iter, metadata_ai, &state, user_ai.key)?;
+        let penalty_destination_ai = accounts_iter.next();
+
+        let (escrow_staking_token_owner, escrow_staking_token_owner_bump) = PdaHelper::find_staking_token_pda(metadata_ai.key, program_id);
+        check_pda(escrow_staking_token_owner_ai, &escrow_staking_token_owner)?;
+
+        if let Some(penalty_destination_ai) = penalty_destination_ai {
+            check_penalty_destination(penalty_destination_ai, &state)?;
+        }
+
+        Ok(UnstakeAccounts {
+            user_ai,
+            user_staking_token_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_staking_token_ai,
+            escrow_staking_token_owner_ai,
+            staking_token_mint_ai,
+            token_program_ai,
+            escrow_staking_token_owner_bump,
+            receipt,
+            penalty_destination_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::GetRewards`
+pub struct GetRewardsAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_rewards_token_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_ai: &'a AccountInfo<'b>,
+    pub rewards_token_mint_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub fee_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_bump: u8,
+    pub global_config: GlobalConfig,
+}
+
+impl<'a, 'b> GetRewardsAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_rewards_token_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_owner_ai = next_account_info(accounts_iter)?;
+        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+        let global_config_ai = next_account_info(accounts_iter)?;
+        let fee_token_ai = next_account_info(accounts_iter)?;
+        let position_owner_ai = accounts_iter.next();
+
+        check_signer(user_ai)?;
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        let reward = state.reward(0)?;
+        check_mint(rewards_token_mint_ai, &reward.mint)?;
+
+        let global_config = load_global_config(program_id, global_config_ai)?;
+        check_fee_token_account(fee_token_ai, &reward.mint, &global_config)?;
+        check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+
+        let owner = position_owner_ai.map_or(*user_ai.key, |ai| *ai.key);
+        check_user_state(program_id, metadata_ai, &owner, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+
+        let user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        if owner != *user_ai.key && user_state.claim_delegate != Some(*user_ai.key) {
+            msg!("Account {} is not the claim delegate of {}", user_ai.key, owner);
+            return Err(StakingError::InvalidClaimDelegate.into());
+        }
+
+        let reward_destination = match user_state.reward_destination {
+            Some(reward_destination) => Some(reward_destination),
+            None if owner != *user_ai.key => Some(owner),
+            None => None,
+        };
+
+        if let Some(reward_destination) = reward_destination {
+            check_reward_destination(user_rewards_token_ai, &reward_destination)?;
+        }
+
+        let (escrow_rewards_token_owner, escrow_rewards_token_owner_bump) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+        check_pda(escrow_rewards_token_owner_ai, &escrow_rewards_token_owner)?;
+
+        Ok(GetRewardsAccounts {
+            user_ai,
+            user_rewards_token_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            rewards_token_mint_ai,
+            token_program_ai,
+            fee_token_ai,
+            escrow_rewards_token_owner_bump,
+            global_config,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::SetRewardRate`
+pub struct SetRewardRateAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> SetRewardRateAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], reward_index: usize) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+
+        let reward = state.reward(reward_index)?;
+        check_mint(rewards_token_mint_ai, &reward.mint)?;
+        check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+
+        Ok(SetRewardRateAccounts {
+            admin_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::NotifyRewardAmount`
+pub struct NotifyRewardAmountAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub admin_rewards_token_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> NotifyRewardAmountAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], reward_index: usize) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let admin_rewards_token_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+
+        let reward = state.reward(reward_index)?;
+        check_mint(rewards_token_mint_ai, &reward.mint)?;
+        check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+
+        Ok(NotifyRewardAmountAccounts {
+            admin_ai,
+            admin_rewards_token_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            token_program_ai,
+        })
+    }
+}
+
+/// Validated accounts of admin instructions which touch only the metadata account
+pub struct AdminAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AdminAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+
+        Ok(AdminAccounts {
+            admin_ai,
+            metadata_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::AcceptAdmin`
+pub struct AcceptAdminAccounts<'a, 'b> {
+    pub pending_admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AcceptAdminAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let pending_admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+
+        check_signer(pending_admin_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+
+        if state.pending_admin != Some(*pending_admin_ai.key) {
+            msg!("Signer {} is not the pending admin", pending_admin_ai.key);
+            return Err(StakingError::Unauthorized.into());
+        }
+
+        Ok(AcceptAdminAccounts {
+            pending_admin_ai,
+            metadata_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::AddRewardToken`
+pub struct AddRewardTokenAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub rewards_token_mint_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> AddRewardTokenAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+
+        let (escrow_rewards_token_owner, _nonce) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+        check_escrow_token_account(escrow_rewards_token_ai, rewards_token_mint_ai.key, &escrow_rewards_token_owner)?;
+
+        Ok(AddRewardTokenAccounts {
+            admin_ai,
+            metadata_ai,
+            rewards_token_mint_ai,
+            escrow_rewards_token_ai,
+        })
+    }
+}
+
+/// Accounts of a single reward stream paid by `StakingInstruction::ClaimRewards`
+pub struct RewardClaimAccounts<'a, 'b> {
+    pub reward_index: usize,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_ai: &'a AccountInfo<'b>,
+    pub user_rewards_token_ai: &'a AccountInfo<'b>,
+    pub fee_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_bump: u8,
+}
+
+/// Validated accounts of `StakingInstruction::ClaimRewards`
+pub struct ClaimRewardsAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub global_config: GlobalConfig,
+    pub rewards: Vec<RewardClaimAccounts<'a, 'b>>,
+}
+
+impl<'a, 'b> ClaimRewardsAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], reward_index: Option<usize>) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+        let global_config_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+
+        let global_config = load_global_config(program_id, global_config_ai)?;
+
+        let reward_indexes = match reward_index {
+            Some(reward_index) => {
+                state.reward(reward_index)?;
+                reward_index..reward_index + 1
+            },
+            None => 0..state.rewards.len(),
+        };
+
+        let mut rewards = Vec::with_capacity(reward_indexes.len());
+
+        for reward_index in reward_indexes {
+            let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+            let escrow_rewards_token_owner_ai = next_account_info(accounts_iter)?;
+            let user_rewards_token_ai = next_account_info(accounts_iter)?;
+            let fee_token_ai = next_account_info(accounts_iter)?;
+
+            let reward = state.reward(reward_index)?;
+            check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+            check_fee_token_account(fee_token_ai, &reward.mint, &global_config)?;
+
+            let (escrow_rewards_token_owner, escrow_rewards_token_owner_bump) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+            check_pda(escrow_rewards_token_owner_ai, &escrow_rewards_token_owner)?;
+
+            rewards.push(RewardClaimAccounts {
+                reward_index,
+                escrow_rewards_token_ai,
+                escrow_rewards_token_owner_ai,
+                user_rewards_token_ai,
+                fee_token_ai,
+                escrow_rewards_token_owner_bump,
+            });
+        }
+
+        Ok(ClaimRewardsAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            token_program_ai,
+            global_config,
+            rewards,
+        })
+    }
+}
+
+/// Validated accounts of instructions which only update the user position,
+/// like `StakingInstruction::SetClaimDelegate`
+pub struct UserPositionAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> UserPositionAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+
+        Ok(UserPositionAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::RequestUnstake` and `StakingInstruction::CancelUnbonding`
+pub struct UnbondingAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub receipt: Option<ReceiptAccounts<'a, 'b>>,
+}
+
+impl<'a, 'b> UnbondingAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+        let receipt = next_receipt_accounts(program_id, accounts_iter, metadata_ai, &state, user_ai.key)?;
+
+        Ok(UnbondingAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            receipt,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::Compound`
+pub struct CompoundAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub fee_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_bump: u8,
+    pub global_config: GlobalConfig,
+    pub receipt: Option<ReceiptAccounts<'a, 'b>>,
+}
+
+impl<'a, 'b> CompoundAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], reward_index: usize) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_owner_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+        let global_config_ai = next_account_info(accounts_iter)?;
+        let fee_token_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        let reward = state.reward(reward_index)?;
+
+        if reward.mint != state.staking_token_mint {
+            msg!("Reward stream {} pays {}, but the pool stakes {}", reward_index, reward.mint, state.staking_token_mint);
+            return Err(StakingError::MintMismatch.into());
+        }
+
+        check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+        check_escrow(escrow_staking_token_ai, &state.staking_escrow)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+
+        let (escrow_rewards_token_owner, escrow_rewards_token_owner_bump) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+        check_pda(escrow_rewards_token_owner_ai, &escrow_rewards_token_owner)?;
+
+        let global_config = load_global_config(program_id, global_config_ai)?;
+        check_fee_token_account(fee_token_ai, &reward.mint, &global_config)?;
+
+        let receipt = next_receipt_accounts(program_id, accounts_iter, metadata_ai, &state, user_ai.key)?;
+
+        Ok(CompoundAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            escrow_staking_token_ai,
+            token_program_ai,
+            fee_token_ai,
+            escrow_rewards_token_owner_bump,
+            global_config,
+            receipt,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::ClosePosition`
+pub struct ClosePositionAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub destination_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> ClosePositionAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let destination_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        load_metadata(program_id, metadata_ai)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+
+        if destination_ai.key == user_state_ai.key {
+            msg!("Rent destination cannot be the closed position");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Ok(ClosePositionAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            destination_ai,
+        })
+    }
+}
+
+/// Accounts of a single reward stream closed by `StakingInstruction::ClosePool`
+pub struct PoolRewardAccounts<'a, 'b> {
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_ai: &'a AccountInfo<'b>,
+    pub rewards_destination_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_bump: u8,
+}
+
+/// Validated accounts of `StakingInstruction::ClosePool`
+pub struct ClosePoolAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_owner_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub staking_destination_ai: &'a AccountInfo<'b>,
+    pub escrow_staking_token_owner_bump: u8,
+    pub rewards: Vec<PoolRewardAccounts<'a, 'b>>,
+}
+
+impl<'a, 'b> ClosePoolAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
+        let escrow_staking_token_owner_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+        let staking_destination_ai = next_account_info(accounts_iter)?;
+
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+        check_escrow(escrow_staking_token_ai, &state.staking_escrow)?;
+
+        let (escrow_staking_token_owner, escrow_staking_token_owner_bump) = PdaHelper::find_staking_token_pda(metadata_ai.key, program_id);
+        check_pda(escrow_staking_token_owner_ai, &escrow_staking_token_owner)?;
+
+        let mut rewards = Vec::with_capacity(state.rewards.len());
+
+        for reward in state.rewards.iter() {
+            let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+            let escrow_rewards_token_owner_ai = next_account_info(accounts_iter)?;
+            let rewards_destination_ai = next_account_info(accounts_iter)?;
+
+            check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+
+            let (escrow_rewards_token_owner, escrow_rewards_token_owner_bump) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+            check_pda(escrow_rewards_token_owner_ai, &escrow_rewards_token_owner)?;
+
+            rewards.push(PoolRewardAccounts {
+                escrow_rewards_token_ai,
+                escrow_rewards_token_owner_ai,
+                rewards_destination_ai,
+                escrow_rewards_token_owner_bump,
+            });
+        }
+
+        Ok(ClosePoolAccounts {
+            admin_ai,
+            metadata_ai,
+            escrow_staking_token_ai,
+            escrow_staking_token_owner_ai,
+            token_program_ai,
+            staking_destination_ai,
+            escrow_staking_token_owner_bump,
+            rewards,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::WithdrawExcessRewards`
+pub struct WithdrawExcessRewardsAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_ai: &'a AccountInfo<'b>,
+    pub destination_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_owner_bump: u8,
+}
+
+impl<'a, 'b> WithdrawExcessRewardsAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], reward_index: usize) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_owner_ai = next_account_info(accounts_iter)?;
+        let destination_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+
+        let reward = state.reward(reward_index)?;
+        check_escrow(escrow_rewards_token_ai, &reward.escrow)?;
+
+        let (escrow_rewards_token_owner, escrow_rewards_token_owner_bump) = PdaHelper::find_rewards_token_pda(metadata_ai.key, program_id);
+        check_pda(escrow_rewards_token_owner_ai, &escrow_rewards_token_owner)?;
+
+        Ok(WithdrawExcessRewardsAccounts {
+            admin_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            destination_ai,
+            token_program_ai,
+            escrow_rewards_token_owner_bump,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::FundRewards`
+pub struct FundRewardsAccounts<'a, 'b> {
+    pub funder_ai: &'a AccountInfo<'b>,
+    pub funder_rewards_token_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub escrow_rewards_token_ai: &'a AccountInfo<'b>,
+    pub token_program_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> FundRewardsAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], reward_index: usize) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let funder_ai = next_account_info(accounts_iter)?;
+        let funder_rewards_token_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
+        let token_program_ai = next_account_info(accounts_iter)?;
+
+        check_signer(funder_ai)?;
+        check_token_program(token_program_ai)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_escrow(escrow_rewards_token_ai, &state.reward(reward_index)?.escrow)?;
+
+        Ok(FundRewardsAccounts {
+            funder_ai,
+            funder_rewards_token_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            token_program_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::CreateGlobalConfig`
+pub struct CreateGlobalConfigAccounts<'a, 'b> {
+    pub authority_ai: &'a AccountInfo<'b>,
+    pub global_config_ai: &'a AccountInfo<'b>,
+    pub system_program_ai: &'a AccountInfo<'b>,
+    pub global_config_bump: u8,
+}
+
+impl<'a, 'b> CreateGlobalConfigAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let authority_ai = next_account_info(accounts_iter)?;
+        let global_config_ai = next_account_info(accounts_iter)?;
+        let system_program_ai = next_account_info(accounts_iter)?;
+        let program_data_ai = next_account_info(accounts_iter)?;
+
+        check_signer(authority_ai)?;
+        check_system_program(system_program_ai)?;
+        check_upgrade_authority(program_id, program_data_ai, authority_ai)?;
+
+        let (global_config_pda, global_config_bump) = PdaHelper::find_global_config_pda(program_id);
+        check_pda(global_config_ai, &global_config_pda)?;
+
+        if !global_config_ai.try_data_is_empty()? {
+            msg!("Global config {} is already initialized", global_config_ai.key);
+            return Err(StakingError::AlreadyInitialized.into());
+        }
+
+        Ok(CreateGlobalConfigAccounts {
+            authority_ai,
+            global_config_ai,
+            system_program_ai,
+            global_config_bump,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::UpdateGlobalConfig`
+pub struct UpdateGlobalConfigAccounts<'a, 'b> {
+    pub authority_ai: &'a AccountInfo<'b>,
+    pub global_config_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> UpdateGlobalConfigAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let authority_ai = next_account_info(accounts_iter)?;
+        let global_config_ai = next_account_info(accounts_iter)?;
+
+        check_signer(authority_ai)?;
+
+        let global_config = load_global_config(program_id, global_config_ai)?;
+
+        if !global_config.is_initialized {
+            msg!("Global config {} is not initialized", global_config_ai.key);
+            return Err(StakingError::NotInitialized.into());
+        }
+
+        if global_config.authority != *authority_ai.key {
+            msg!("Only protocol authority {} can update the global config", global_config.authority);
+            return Err(StakingError::Unauthorized.into());
+        }
+
+        Ok(UpdateGlobalConfigAccounts {
+            authority_ai,
+            global_config_ai,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::TransferPosition`
+pub struct TransferPositionAccounts<'a, 'b> {
+    pub user_ai: &'a AccountInfo<'b>,
+    pub user_state_ai: &'a AccountInfo<'b>,
+    pub recipient_state_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub system_program_ai: &'a AccountInfo<'b>,
+    pub recipient_state_bump: u8,
+    pub receipt: Option<ReceiptAccounts<'a, 'b>>,
+    /// Same receipt accounts with the receipt token account of the recipient
+    pub recipient_receipt: Option<ReceiptAccounts<'a, 'b>>,
+    /// Whether the recipient wallet signed, which lets the transfer extend its lock and penalty window
+    pub recipient_signed: bool,
+}
+
+impl<'a, 'b> TransferPositionAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], to: &Pubkey) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let user_ai = next_account_info(accounts_iter)?;
+        let user_state_ai = next_account_info(accounts_iter)?;
+        let recipient_state_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let system_program_ai = next_account_info(accounts_iter)?;
+
+        check_signer(user_ai)?;
+        check_system_program(system_program_ai)?;
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_user_state(program_id, metadata_ai, user_ai.key, user_state_ai, false)?;
+        check_position_generation(user_state_ai, &state)?;
+
+        if to == user_ai.key {
+            msg!("Cannot transfer a position to its owner");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let recipient_state_bump = check_user_state(program_id, metadata_ai, to, recipient_state_ai, true)?;
+        let receipt = next_receipt_accounts(program_id, accounts_iter, metadata_ai, &state, user_ai.key)?;
+        let recipient_receipt = match receipt {
+            Some(receipt) => {
+                let recipient_receipt_token_ai = next_account_info(accounts_iter)?;
+                check_receipt_token_account(recipient_receipt_token_ai, receipt.receipt_mint_ai.key, to)?;
+
+                Some(ReceiptAccounts {
+                    user_receipt_token_ai: recipient_receipt_token_ai,
+                    ..receipt
+                })
+            },
+            None => None,
+        };
+
+        let recipient_signed = match accounts_iter.next() {
+            Some(recipient_ai) => {
+                if recipient_ai.key != to {
+                    msg!("Passed recipient: {}, expected: {}", recipient_ai.key, to);
+                    return Err(ProgramError::InvalidArgument);
+                }
+
+                recipient_ai.is_signer
+            },
+            None => false,
+        };
+
+        Ok(TransferPositionAccounts {
+            user_ai,
+            user_state_ai,
+            recipient_state_ai,
+            metadata_ai,
+            system_program_ai,
+            recipient_state_bump,
+            receipt,
+            recipient_receipt,
+            recipient_signed,
+        })
+    }
+}
+
+/// Validated accounts of `StakingInstruction::SetReceiptMint`
+pub struct SetReceiptMintAccounts<'a, 'b> {
+    pub admin_ai: &'a AccountInfo<'b>,
+    pub metadata_ai: &'a AccountInfo<'b>,
+    pub receipt_mint_ai: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> SetReceiptMintAccounts<'a, 'b> {
+    pub fn try_from_accounts(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let admin_ai = next_account_info(accounts_iter)?;
+        let metadata_ai = next_account_info(accounts_iter)?;
+        let receipt_mint_ai = next_account_info(accounts_iter)?;
+        let staking_token_mint_ai = next_account_info(accounts_iter)?;
+
+        let state = load_metadata(program_id, metadata_ai)?;
+        check_admin(admin_ai, &state)?;
+        check_mint(staking_token_mint_ai, &state.staking_token_mint)?;
+
+        if *receipt_mint_ai.owner != spl_token::id() || *staking_token_mint_ai.owner != spl_token::id() {
+            msg!("Receipt mint {} or staking token mint {} is not a token mint", receipt_mint_ai.key, staking_token_mint_ai.key);
+            return Err(StakingError::InvalidAccountOwner.into());
+        }
+
+        let receipt_mint = Mint::unpack(&receipt_mint_ai.try_borrow_data()?)?;
+        let staking_token_mint = Mint::unpack(&staking_token_mint_ai.try_borrow_data()?)?;
+        let (receipt_authority, _receipt_authority_bump) = PdaHelper::find_receipt_authority_pda(metadata_ai.key, program_id);
+
+        if receipt_mint.mint_authority != COption::Some(receipt_authority)
+            || receipt_mint.freeze_authority.is_some()
+            || receipt_mint.supply != 0
+            || receipt_mint.decimals != staking_token_mint.decimals {
+            msg!("Receipt mint {} needs mint authority {}, no freeze authority, no supply and {} decimals", receipt_mint_ai.key, receipt_authority, staking_token_mint.decimals);
+            return Err(StakingError::InvalidReceiptMint.into());
+        }
+
+        Ok(SetReceiptMintAccounts {
+            admin_ai,
+            metadata_ai,
+            receipt_mint_ai,
+        })
+    }
+}
+
+fn check_signer(account_ai: &AccountInfo) -> Result<(), ProgramError> {
+    if !account_ai.is_signer {
+        msg!("Account {} must be a signer", account_ai.key);
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    Ok(())
+}
+
+fn check_admin(admin_ai: &AccountInfo, state: &StakingState) -> Result<(), ProgramError> {
+    check_signer(admin_ai)?;
+
+    if state.admin != *admin_ai.key {
+        msg!("Only admin can perform this action. Admin: {}, signer: {}", state.admin, admin_ai.key);
+        return Err(StakingError::Unauthorized.into());
+    }
+
+    Ok(())
+}
+
+fn check_token_program(token_program_ai: &AccountInfo) -> Result<(), ProgramError> {
+    if *token_program_ai.key != spl_token::id() {
+        msg!("Passed token program: {}, expected: {}", token_program_ai.key, spl_token::id());
+        return Err(StakingError::InvalidProgramId.into());
+    }
+
+    Ok(())
+}
+
+fn check_system_program(system_program_ai: &AccountInfo) -> Result<(), ProgramError> {
+    if *system_program_ai.key != system_program::id() {
+        msg!("Passed system program: {}, expected: {}", system_program_ai.key, system_program::id());
+        return Err(StakingError::InvalidProgramId.into());
+    }
+
+    Ok(())
+}
+
+fn check_pda(account_ai: &AccountInfo, expected: &Pubkey) -> Result<(), ProgramError> {
+    if account_ai.key != expected {
+        msg!("Passed pda: {}, computed: {}", account_ai.key, expected);
+        return Err(StakingError::InvalidPda.into());
+    }
+
+    Ok(())
+}
+
+fn check_mint(mint_ai: &AccountInfo, expected: &Pubkey) -> Result<(), ProgramError> {
+    if mint_ai.key != expected {
+        msg!("Passed mint: {}, pool mint: {}", mint_ai.key, expected);
+        return Err(StakingError::InvalidMint.into());
+    }
+
+    Ok(())
+}
+
+fn check_escrow(escrow_ai: &AccountInfo, expected: &Pubkey) -> Result<(), ProgramError> {
+    if escrow_ai.key != expected {
+        msg!("Passed escrow: {}, pool escrow: {}", escrow_ai.key, expected);
+        return Err(StakingError::InvalidEscrow.into());
+    }
+
+    Ok(())
+}
+
+fn check_escrow_token_account(escrow_ai: &AccountInfo, mint: &Pubkey, escrow_owner: &Pubkey) -> Result<(), ProgramError> {
+    if *escrow_ai.owner != spl_token::id() {
+        msg!("Escrow {} is not a token account", escrow_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    let escrow = Account::unpack(&escrow_ai.try_borrow_data()?)?;
+
+    if escrow.mint != *mint {
+        msg!("Escrow {} holds mint {}, expected {}", escrow_ai.key, escrow.mint, mint);
+        return Err(StakingError::InvalidMint.into());
+    }
+
+    if escrow.owner != *escrow_owner {
+        msg!("Escrow token account must have pda as owner. Current owner {}, pda {}", escrow.owner, escrow_owner);
+        return Err(StakingError::InvalidEscrowOwner.into());
+    }
+
+    if escrow.delegate.is_some() || escrow.close_authority.is_some() {
+        msg!("Escrow {} must not have a delegate or close authority", escrow_ai.key);
+        return Err(StakingError::InvalidEscrowOwner.into());
+    }
+
+    Ok(())
+}
+
+fn check_penalty_destination(penalty_destination_ai: &AccountInfo, state: &StakingState) -> Result<(), ProgramError> {
+    if state.penalty_destination() != Some(*penalty_destination_ai.key) {
+        msg!("Account {} is not the penalty destination of the pool", penalty_destination_ai.key);
+        return Err(StakingError::InvalidPenaltyDestination.into());
+    }
+
+    Ok(())
+}
+
+/// Loads the global config. A config which was never created charges no fee.
+fn load_global_config(program_id: &Pubkey, global_config_ai: &AccountInfo) -> Result<GlobalConfig, ProgramError> {
+    let (global_config_pda, _nonce) = PdaHelper::find_global_config_pda(program_id);
+    check_pda(global_config_ai, &global_config_pda)?;
+
+    if global_config_ai.try_data_is_empty()? {
+        return Ok(GlobalConfig::default());
+    }
+
+    if global_config_ai.owner != program_id {
+        msg!("Global config {} is not owned by the program", global_config_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    GlobalConfig::unpack(&mut global_config_ai.try_borrow_mut_data()?)
+}
+
+fn check_fee_token_account(fee_token_ai: &AccountInfo, mint: &Pubkey, global_config: &GlobalConfig) -> Result<(), ProgramError> {
+    if global_config.fee_bps == 0 {
+        return Ok(());
+    }
+
+    if *fee_token_ai.owner != spl_token::id() {
+        msg!("Fee account {} is not a token account", fee_token_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    let fee_token = Account::unpack(&fee_token_ai.try_borrow_data()?)?;
+
+    if fee_token.mint != *mint {
+        msg!("Fee account {} holds mint {}, expected {}", fee_token_ai.key, fee_token.mint, mint);
+        return Err(StakingError::InvalidMint.into());
+    }
+
+    if fee_token.owner != global_config.fee_recipient {
+        msg!("Fee account {} is owned by {}, expected fee recipient {}", fee_token_ai.key, fee_token.owner, global_config.fee_recipient);
+        return Err(StakingError::InvalidFeeRecipient.into());
+    }
+
+    Ok(())
+}
+
+/// Takes the receipt accounts following the instruction accounts, none for a pool without a receipt mint.
+/// The receipt token account must be owned by `owner`.
+fn next_receipt_accounts<'a, 'b>(
+    program_id: &Pubkey, 
+    accounts_iter: &mut Iter<'a, AccountInfo<'b>>, 
+    metadata_ai: &AccountInfo, 
+    state: &StakingState,
+    owner: &Pubkey) -> Result<Option<ReceiptAccounts<'a, 'b>>, ProgramError> {
+    let receipt_mint = match state.receipt_mint {
+        Some(receipt_mint) => receipt_mint,
+        None => return Ok(None),
+    };
+
+    let receipt_mint_ai = next_account_info(accounts_iter)?;
+    let user_receipt_token_ai = next_account_info(accounts_iter)?;
+    let receipt_authority_ai = next_account_info(accounts_iter)?;
+    let token_program_ai = next_account_info(accounts_iter)?;
+
+    check_token_program(token_program_ai)?;
+    check_mint(receipt_mint_ai, &receipt_mint)?;
+    check_receipt_token_account(user_receipt_token_ai, &receipt_mint, owner)?;
+
+    let (receipt_authority, receipt_authority_bump) = PdaHelper::find_receipt_authority_pda(metadata_ai.key, program_id);
+    check_pda(receipt_authority_ai, &receipt_authority)?;
+
+    Ok(Some(ReceiptAccounts {
+        receipt_mint_ai,
+        user_receipt_token_ai,
+        receipt_authority_ai,
+        token_program_ai,
+        receipt_authority_bump,
+    }))
+}
+
+fn check_receipt_token_account(receipt_token_ai: &AccountInfo, receipt_mint: &Pubkey, owner: &Pubkey) -> Result<(), ProgramError> {
+    if *receipt_token_ai.owner != spl_token::id() {
+        msg!("Receipt account {} is not a token account", receipt_token_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    let receipt_token = Account::unpack(&receipt_token_ai.try_borrow_data()?)?;
+
+    if receipt_token.mint != *receipt_mint {
+        msg!("Receipt account {} holds mint {}, expected {}", receipt_token_ai.key, receipt_token.mint, receipt_mint);
+        return Err(StakingError::InvalidReceiptMint.into());
+    }
+
+    if receipt_token.owner != *owner {
+        msg!("Receipt account {} is owned by {}, expected position owner {}", receipt_token_ai.key, receipt_token.owner, owner);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    Ok(())
+}
+
+fn check_reward_destination(user_rewards_token_ai: &AccountInfo, reward_destination: &Pubkey) -> Result<(), ProgramError> {
+    if *user_rewards_token_ai.owner != spl_token::id() {
+        msg!("Rewards account {} is not a token account", user_rewards_token_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    let user_rewards_token = Account::unpack(&user_rewards_token_ai.try_borrow_data()?)?;
+
+    if user_rewards_token.owner != *reward_destination {
+        msg!("Rewards account {} is owned by {}, expected reward destination {}", user_rewards_token_ai.key, user_rewards_token.owner, reward_destination);
+        return Err(StakingError::InvalidRewardDestination.into());
+    }
+
+    Ok(())
+}
+
+fn load_metadata(program_id: &Pubkey, metadata_ai: &AccountInfo) -> Result<StakingState, ProgramError> {
+    if metadata_ai.owner != program_id {
+        msg!("Metadata {} is not owned by the program", metadata_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+    if !state.is_initialized {
+        msg!("Metadata {} is not initialized", metadata_ai.key);
+        return Err(StakingError::NotInitialized.into());
+    }
+
+    let (metadata_pda, _nonce) = PdaHelper::find_metadata_pda(&state.staking_token_mint, &state.reward(0)?.mint, &state.creator, program_id);
+    check_pda(metadata_ai, &metadata_pda)?;
+
+    Ok(state)
+}
+
+/// Checks the user state pda and returns its bump seed. An empty account is accepted only when
+/// the instruction is allowed to create it.
+fn check_user_state(program_id: &Pubkey, metadata_ai: &AccountInfo, user: &Pubkey, user_state_ai: &AccountInfo, allow_empty: bool) -> Result<u8, ProgramError> {
+    let (user_state_pda, user_state_bump) = PdaHelper::find_user_state_pda(metadata_ai.key, user, program_id);
+    check_pda(user_state_ai, &user_state_pda)?;
+
+    if user_state_ai.try_data_is_empty()? {
+        if !allow_empty {
+            msg!("User state {} is not initialized", user_state_ai.key);
+            return Err(StakingError::NotInitialized.into());
+        }
+    } else if user_state_ai.owner != program_id {
+        msg!("User state {} is not owned by the program", user_state_ai.key);
+        return Err(StakingError::InvalidAccountOwner.into());
+    }
+
+    Ok(user_state_bump)
+}
+
+/// Rejects a position left over from a closed pool. It can only be closed, or opened again by a deposit.
+fn check_position_generation(user_state_ai: &AccountInfo, state: &StakingState) -> Result<(), ProgramError> {
+    let user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+    if user_state.generation != state.generation {
+        msg!("Position {} was opened in a closed pool", user_state_ai.key);
+        return Err(StakingError::StalePosition.into());
+    }
+
+    Ok(())
+}
+
+/// The global config is program wide, so only the upgrade authority of the program may create it.
+fn check_upgrade_authority(program_id: &Pubkey, program_data_ai: &AccountInfo, authority_ai: &AccountInfo) -> Result<(), ProgramError> {
+    let (program_data, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    check_pda(program_data_ai, &program_data)?;
+
+    if *program_data_ai.owner != bpf_loader_upgradeable::id() {
+        msg!("Program data account {} is not owned by the upgradeable loader", program_data_ai.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // `UpgradeableLoaderState::ProgramData` is bincode encoded as a u32 tag (3), the u64 slot
+    // and an optional upgrade authority.
+    let data = program_data_ai.try_borrow_data()?;
+    let upgrade_authority = match data.get(..45) {
+        Some(header) if header[..4] == 3u32.to_le_bytes() && header[12] == 1 => Pubkey::new(&header[13..45]),
+        _ => {
+            msg!("Program {} has no upgrade authority", program_id);
+            return Err(StakingError::InvalidProtocolAuthority.into());
+        }
+    };
+
+    if upgrade_authority != *authority_ai.key {
+        msg!("Signer {} is not the upgrade authority {}", authority_ai.key, upgrade_authority);
+        return Err(StakingError::InvalidProtocolAuthority.into());
+    }
+
+    Ok(())
+}
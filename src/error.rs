@@ -0,0 +1,106 @@
+use solana_program::{
+    decode_error::DecodeError,
+    program_error::ProgramError,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StakingError {
+    /// Account data cannot be deserialized into the expected state
+    InvalidStateData,
+    /// Arithmetic operation overflowed or underflowed
+    MathOverflow,
+    /// Passed account does not match the computed program address
+    InvalidPda,
+    /// Escrow token account is not owned by the escrow pda
+    InvalidEscrowOwner,
+    /// Signer is not the pool admin
+    Unauthorized,
+    /// User tries to withdraw more than staked
+    InsufficientStake,
+    /// Rewards duration must be positive
+    InvalidRewardsDuration,
+    /// Rewards escrow does not hold enough tokens for the requested reward rate
+    InsufficientRewardsFunding,
+    /// Account is not owned by the expected program
+    InvalidAccountOwner,
+    /// Passed program account is not the expected program
+    InvalidProgramId,
+    /// Passed mint does not match the pool mint
+    InvalidMint,
+    /// Passed escrow token account does not belong to the pool
+    InvalidEscrow,
+    /// Account is already initialized
+    AlreadyInitialized,
+    /// Account is not initialized yet
+    NotInitialized,
+    /// Operation requires a pool without stake
+    PoolNotEmpty,
+    /// Operation is paused by the pool admin
+    PoolPaused,
+    /// Reward stream with the given index does not exist
+    InvalidRewardIndex,
+    /// Pool already distributes the maximum number of reward tokens
+    TooManyRewardTokens,
+    /// Pool already distributes this reward token
+    RewardTokenAlreadyAdded,
+    /// Lock duration does not match any lock tier of the pool
+    InvalidLockDuration,
+    /// New lock would end before the current lock of the position
+    LockShortened,
+    /// Stake cannot be withdrawn before the lock ends
+    StakeLocked,
+    /// Lock tiers are empty-duration, below the base multiplier or too many
+    InvalidLockTiers,
+    /// Pool has an unbonding period, so stake must be withdrawn through `RequestUnstake`
+    UnbondingRequired,
+    /// User already has the maximum number of pending unbonding entries
+    TooManyUnbondingEntries,
+    /// Unbonding entry with the given index does not exist
+    InvalidUnbondingIndex,
+    /// None of the unbonding entries has matured yet
+    NoMaturedUnbonding,
+    /// Reward stream mint differs from the staking token mint
+    MintMismatch,
+    /// Position still holds stake, unbonding entries or unclaimed rewards
+    PositionNotEmpty,
+    /// Deposit is below the pool minimum stake amount
+    StakeBelowMinimum,
+    /// Deposit would exceed the pool deposit cap
+    PoolStakeCapExceeded,
+    /// Deposit would exceed the per-user stake cap
+    UserStakeCapExceeded,
+    /// Penalty is above 100% or has no reward stream in the staking token to redistribute through
+    InvalidPenaltyConfig,
+    /// Penalty destination account is missing or is not the configured one
+    InvalidPenaltyDestination,
+    /// Protocol fee is above 100%
+    InvalidFee,
+    /// Fee token account is not owned by the protocol fee recipient
+    InvalidFeeRecipient,
+    /// Signer is neither the position owner nor its claim delegate
+    InvalidClaimDelegate,
+    /// Rewards token account is not owned by the reward destination of the position
+    InvalidRewardDestination,
+    /// Recipient position is boosted differently than the transferred stake
+    LockMismatch,
+    /// Receipt mint is not the pool one, or cannot be controlled by the pool alone
+    InvalidReceiptMint,
+    /// Signer is not the upgrade authority of the program
+    InvalidProtocolAuthority,
+    /// Transfer would extend the lock or penalty window of a recipient holding stake without its signature
+    RecipientSignatureRequired,
+    /// Position was opened in a closed pool previously initialized at the same address
+    StalePosition,
+}
+
+impl From<StakingError> for ProgramError {
+    fn from(e: StakingError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl<T> DecodeError<T> for StakingError {
+    fn type_of() -> &'static str {
+        "StakingError"
+    }
+}
@@ -1,7 +1,14 @@
 use borsh::{BorshSerialize, BorshDeserialize};
+use solana_program::pubkey::Pubkey;
+use crate::state::{LockTier, PenaltyConfig};
 
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
 pub enum StakingInstruction {
+    /// Creates the pool. Zero stake caps are unlimited. The first reward stream emits `reward_rate`
+    /// tokens per second for `reward_duration` seconds, which the rewards escrow must already hold.
+    /// The metadata account is seeded by both mints and the admin, so every admin gets its own pool.
+    /// Escrows must be owned by the staking and rewards token owner pdas of this pool,
+    /// which are seeded by the metadata account, and have no delegate or close authority.
     ///
     /// 0. [signer] - authority/admin
     /// 1. [writable] - metadata account(pda)
@@ -11,7 +18,13 @@ pub enum StakingInstruction {
     /// 5. [writable] - escrow rewards token account
     /// 6. [] - system program
     /// 7. [] - token program
-    Init,
+    Init {
+        reward_rate: u64,
+        reward_duration: u64,
+        max_total_stake: u64,
+        max_stake_per_user: u64,
+        min_stake_amount: u64
+    },
 
     ///
     /// 0. [signer] - user account who want to stake
@@ -22,10 +35,15 @@ pub enum StakingInstruction {
     /// 5. [] - staking token mint account
     /// 6. [] - token program
     /// 7. [] - system program
+    /// 8. [writable] - receipt mint account, only when the pool has a receipt mint
+    /// 9. [writable] - receipt token account owned by the position owner
+    /// 10. [] - receipt authority account(pda)
+    /// 11. [] - token program
     Stake {
         amount: u64
     },
 
+    /// Charges the pool penalty when called too soon after the last stake.
     ///
     /// 0. [signer] - user account who want to unstake
     /// 1. [writable] - user staking token account 
@@ -35,10 +53,17 @@ pub enum StakingInstruction {
     /// 5. [] - escrow staking token owner account(pda)
     /// 6. [] - staking token mint account
     /// 7. [] - token program
+    /// 8. receipt accounts 8 to 11 as for `Stake`, only when the pool has a receipt mint.
+    ///    Receipts for the whole `amount` are burned from the user receipt token account.
+    /// 12. [writable] - optional penalty destination: treasury token account, or escrow
+    ///     rewards token account of the staking token stream when redistributing.
+    ///     Passed at 8 when the pool has no receipt mint.
     Unstake {
         amount: u64
     },
 
+    /// Claims rewards of the first reward stream. The protocol fee goes to the fee token account,
+    /// which is only checked while a fee is charged.
     ///
     /// 0. [signer] - user account who want to claim rewards
     /// 1. [writable] - user rewards token account 
@@ -48,5 +73,344 @@ pub enum StakingInstruction {
     /// 5. [] - escrow rewards token owner account(pda)
     /// 6. [] - rewards token mint account
     /// 7. [] - token program
-    GetRewards
+    /// 8. [] - global config account(pda)
+    /// 9. [writable] - fee recipient rewards token account
+    /// 10. [] - optional position owner account, when the signer is its claim delegate
+    ///
+    /// The user rewards token account must be owned by the reward destination of the position.
+    /// Without one, a delegated claim pays to the position owner and the owner may pay anywhere.
+    GetRewards,
+
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    /// 2. [] - escrow rewards token account of the stream
+    /// 3. [] - rewards token mint account of the stream
+    SetRewardRate {
+        reward_index: u8,
+        reward_rate: u64
+    },
+
+    /// Starts a rewards period paying `amount` together with what is left of the current
+    /// period and the unscheduled funding of the stream over `duration` seconds.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - admin rewards token account
+    /// 2. [writable] - metadata account(pda)
+    /// 3. [writable] - escrow rewards token account of the stream
+    /// 4. [] - rewards token mint account of the stream
+    /// 5. [] - token program
+    NotifyRewardAmount {
+        reward_index: u8,
+        amount: u64,
+        duration: u64
+    },
+
+    /// Stops the current rewards period. Allowed only while nothing is staked.
+    /// The reward accumulator is kept so existing user checkpoints stay valid.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    ResetPool,
+
+    /// Proposes a new admin. The change takes effect once the new admin accepts it.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    ProposeAdmin {
+        new_admin: Pubkey
+    },
+
+    ///
+    /// 0. [signer] - pending admin account
+    /// 1. [writable] - metadata account(pda)
+    AcceptAdmin,
+
+    /// Pauses or resumes pool operations. Rewards stop accruing while all of them are paused.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    SetPaused {
+        stake_paused: bool,
+        unstake_paused: bool,
+        claim_paused: bool
+    },
+
+    /// Withdraws the whole staked balance together with matured unbonding entries
+    /// and forfeits pending rewards. With an unbonding period the staked balance becomes
+    /// an unbonding entry instead, withdrawn by `WithdrawUnbonded` once it matures.
+    /// The early unstake penalty is charged on the staked balance as for `RequestUnstake`,
+    /// and the penalties of paid unbonding entries are withheld.
+    /// Works while the pool is paused, but not while the stake is locked.
+    ///
+    /// 0. [signer] - user account who want to withdraw
+    /// 1. [writable] - user staking token account 
+    /// 2. [writable] - user state account(pda)
+    /// 3. [writable] - metadata account(pda)
+    /// 4. [writable] - escrow staking token account
+    /// 5. [] - escrow staking token owner account(pda)
+    /// 6. [] - staking token mint account
+    /// 7. [] - token program
+    /// 8. receipt accounts 8 to 11 as for `Stake`, only when the pool has a receipt mint.
+    ///    Receipts for the staked balance are burned, unbonding entries hold none.
+    /// 12. [writable] - optional penalty destination as for `Unstake`
+    EmergencyWithdraw,
+
+    /// Adds a new reward stream to the pool. The escrow must be owned by the rewards
+    /// token owner pda of this pool.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    /// 2. [] - rewards token mint account
+    /// 3. [] - escrow rewards token account
+    AddRewardToken,
+
+    /// Claims rewards of one stream, or of all streams when `reward_index` is none.
+    /// Accounts 5.. are repeated for every claimed stream, in stream order.
+    ///
+    /// 0. [signer] - user account who want to claim rewards
+    /// 1. [writable] - user state account(pda)
+    /// 2. [writable] - metadata account(pda)
+    /// 3. [] - token program
+    /// 4. [] - global config account(pda)
+    /// 5. [writable] - escrow rewards token account
+    /// 6. [] - escrow rewards token owner account(pda)
+    /// 7. [writable] - user rewards token account
+    /// 8. [writable] - fee recipient rewards token account
+    ClaimRewards {
+        reward_index: Option<u8>
+    },
+
+    /// Stakes and locks the whole position until `lock_duration` seconds from now.
+    /// The duration must match a lock tier, whose multiplier then boosts the position.
+    /// A lock can only be extended, never shortened.
+    ///
+    /// Accounts are the same as for `Stake`
+    StakeLocked {
+        amount: u64,
+        lock_duration: u64
+    },
+
+    /// Replaces the lock tiers. Existing locks keep their multiplier until they end.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    SetLockTiers {
+        lock_tiers: Vec<LockTier>
+    },
+
+    /// Sets the cooldown between `RequestUnstake` and `WithdrawUnbonded`.
+    /// Already pending unbonding entries keep their maturity.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    SetUnbondingPeriod {
+        unbonding_period: u64
+    },
+
+    /// Moves `amount` of stake into a new unbonding entry, which stops earning rewards
+    /// and can be withdrawn once the pool unbonding period passes. The early unstake penalty
+    /// is charged on the entry and withheld when it is withdrawn.
+    ///
+    /// 0. [signer] - user account who want to unstake
+    /// 1. [writable] - user state account(pda)
+    /// 2. [writable] - metadata account(pda)
+    /// 3. receipt accounts 8 to 11 as for `Stake`, only when the pool has a receipt mint
+    RequestUnstake {
+        amount: u64
+    },
+
+    /// Withdraws every matured unbonding entry. Their penalties go to the penalty destination.
+    ///
+    /// Accounts are the same as for `Unstake`
+    WithdrawUnbonded,
+
+    /// Cancels a pending unbonding entry and stakes its amount again, dropping its penalty.
+    /// The pool and per user stake caps apply, the minimum stake amount does not.
+    ///
+    /// 0. [signer] - user account
+    /// 1. [writable] - user state account(pda)
+    /// 2. [writable] - metadata account(pda)
+    /// 3. receipt accounts 8 to 11 as for `Stake`, only when the pool has a receipt mint
+    CancelUnbonding {
+        index: u8
+    },
+
+    /// Stakes pending rewards of a stream whose mint is the staking token mint.
+    /// The pool and per user stake caps apply, the minimum stake amount does not.
+    ///
+    /// 0. [signer] - user account
+    /// 1. [writable] - user state account(pda)
+    /// 2. [writable] - metadata account(pda)
+    /// 3. [writable] - escrow rewards token account of the stream
+    /// 4. [] - escrow rewards token owner account(pda)
+    /// 5. [writable] - escrow staking token account
+    /// 6. [] - token program
+    /// 7. [] - global config account(pda)
+    /// 8. [writable] - fee recipient staking token account
+    /// 9. receipt accounts 8 to 11 as for `Stake`, only when the pool has a receipt mint
+    Compound {
+        reward_index: u8
+    },
+
+    /// Closes an empty user state account and sends its rent to the destination.
+    /// Stake, unbonding entries and rewards must be withdrawn and claimed first.
+    /// A position left over from a closed pool forfeits its rounding dust.
+    ///
+    /// 0. [signer] - user account
+    /// 1. [writable] - user state account(pda)
+    /// 2. [writable] - metadata account(pda)
+    /// 3. [writable] - rent destination account
+    ClosePosition,
+
+    /// Retires a pool without stake or unbonding entries, once every reward stream owes no more
+    /// than rounding dust to its positions. Tokens left in the escrows are swept to the given token accounts.
+    /// Escrows and metadata are closed and their rent goes to the admin. Positions of the closed pool
+    /// can only be closed, or opened again by a deposit once the pool is initialized anew.
+    /// Accounts 6.. are repeated for every reward stream, in stream order.
+    ///
+    /// 0. [signer, writable] - admin account
+    /// 1. [writable] - metadata account(pda)
+    /// 2. [writable] - escrow staking token account
+    /// 3. [] - escrow staking token owner account(pda)
+    /// 4. [] - token program
+    /// 5. [writable] - token account receiving leftover staking tokens
+    /// 6. [writable] - escrow rewards token account
+    /// 7. [] - escrow rewards token owner account(pda)
+    /// 8. [writable] - token account receiving leftover rewards
+    ClosePool,
+
+    /// Withdraws rewards of a stream which are neither owed to stakers nor
+    /// committed to the rest of the current rewards period
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    /// 2. [writable] - escrow rewards token account of the stream
+    /// 3. [] - escrow rewards token owner account(pda)
+    /// 4. [writable] - token account receiving the excess rewards
+    /// 5. [] - token program
+    WithdrawExcessRewards {
+        reward_index: u8
+    },
+
+    /// Transfers reward tokens into the escrow of a stream. Anyone can fund a pool.
+    /// With `extend_period` an active rewards period is prolonged at the current rate
+    /// for as long as the amount lasts. Funding left over, or all of it otherwise, is kept
+    /// out of excess rewards and scheduled by the next `NotifyRewardAmount`.
+    ///
+    /// 0. [signer] - funder account
+    /// 1. [writable] - funder rewards token account
+    /// 2. [writable] - metadata account(pda)
+    /// 3. [writable] - escrow rewards token account of the stream
+    /// 4. [] - token program
+    FundRewards {
+        reward_index: u8,
+        amount: u64,
+        extend_period: bool
+    },
+
+    /// Updates the deposit limits. Zero caps are unlimited. Limits only apply to new
+    /// deposits, so existing positions above them are kept.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    SetStakeLimits {
+        max_total_stake: u64,
+        max_stake_per_user: u64,
+        min_stake_amount: u64
+    },
+
+    /// Sets the early unstake penalty. Zero period disables it. Redistribution needs
+    /// a reward stream paying the staking token.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    SetPenaltyConfig {
+        penalty: PenaltyConfig
+    },
+
+    /// Creates the program wide config. Only the upgrade authority of the program can
+    /// create it and becomes the protocol authority.
+    ///
+    /// 0. [signer, writable] - program upgrade authority account
+    /// 1. [writable] - global config account(pda)
+    /// 2. [] - system program
+    /// 3. [] - program data account of this program
+    CreateGlobalConfig {
+        fee_bps: u16,
+        fee_recipient: Pubkey
+    },
+
+    ///
+    /// 0. [signer] - protocol authority account
+    /// 1. [writable] - global config account(pda)
+    UpdateGlobalConfig {
+        authority: Pubkey,
+        fee_bps: u16,
+        fee_recipient: Pubkey
+    },
+
+    /// Stakes tokens of the signer into the position of `beneficiary`.
+    /// The signer also pays the rent of a new position. Unless the signer is the owner, the deposit
+    /// moves the start of the early unstake penalty window to the stake-weighted deposit time
+    /// instead of restarting it, so a new position is always inside the window.
+    ///
+    /// 0. [signer] - funder account
+    /// 1. [writable] - funder staking token account 
+    /// 2. [writable] - escrow staking token account
+    /// 3. [writable] - beneficiary user state account(pda)
+    /// 4. [writable] - metadata account(pda)
+    /// 5. [] - staking token mint account
+    /// 6. [] - token program
+    /// 7. [] - system program
+    /// 8. [writable] - receipt mint account, only when the pool has a receipt mint
+    /// 9. [writable] - receipt token account owned by the beneficiary
+    /// 10. [] - receipt authority account(pda)
+    /// 11. [] - token program
+    StakeFor {
+        beneficiary: Pubkey,
+        amount: u64
+    },
+
+    /// Sets the wallet allowed to claim rewards for the position and the owner
+    /// of the token accounts rewards are paid to. `None` clears either of them.
+    ///
+    /// 0. [signer] - user account
+    /// 1. [writable] - user state account(pda)
+    /// 2. [] - metadata account(pda)
+    SetClaimDelegate {
+        claim_delegate: Option<Pubkey>,
+        reward_destination: Option<Pubkey>
+    },
+
+    /// Moves `amount` of stake and a pro-rated share of unclaimed rewards into the position of `to`.
+    /// The recipient keeps the later lock end and penalty window of both positions, and a recipient
+    /// holding stake must have the same boost and sign when its lock end or penalty window is extended.
+    /// Unbonding entries stay with the sender, while receipts for `amount` are burned from the sender
+    /// and minted to the recipient.
+    /// The signer pays the rent of a new recipient position.
+    ///
+    /// 0. [signer] - user account
+    /// 1. [writable] - user state account(pda)
+    /// 2. [writable] - recipient user state account(pda)
+    /// 3. [writable] - metadata account(pda)
+    /// 4. [] - system program
+    /// 5. receipt accounts 8 to 11 as for `Stake`, only when the pool has a receipt mint
+    /// 9. [writable] - receipt token account owned by the recipient
+    /// 10. [signer] - optional recipient account, required when the transfer extends its windows.
+    ///     Passed at 5 when the pool has no receipt mint.
+    TransferPosition {
+        to: Pubkey,
+        amount: u64
+    },
+
+    /// Starts issuing receipt tokens for stake. Only an empty pool can enable receipts, and only once.
+    /// The receipt mint must have no supply, no freeze authority, the decimals of the staking token
+    /// and the receipt authority pda as mint authority.
+    ///
+    /// 0. [signer] - admin account
+    /// 1. [writable] - metadata account(pda)
+    /// 2. [] - receipt mint account
+    /// 3. [] - staking token mint account
+    SetReceiptMint
 }
\ No newline at end of file
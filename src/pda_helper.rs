@@ -1,33 +1,42 @@
-use solana_program::{
-    pubkey::Pubkey,
-    sysvar::slot_history::AccountInfo
-};
+use solana_program::pubkey::Pubkey;
 
 pub struct PdaHelper;
 
 impl PdaHelper {
 
-    pub fn find_metadata_pda(staking_token_mint_ai: &AccountInfo, rewards_token_mint_ai: &AccountInfo, program_id: &Pubkey) -> (Pubkey, u8) {
+    pub fn find_metadata_pda(staking_token_mint: &Pubkey, rewards_token_mint: &Pubkey, creator: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
         Pubkey::find_program_address(
-            &[&staking_token_mint_ai.key.to_bytes(), &rewards_token_mint_ai.key.to_bytes(), b"metadata"], 
+            &[&staking_token_mint.to_bytes(), &rewards_token_mint.to_bytes(), &creator.to_bytes(), b"metadata"], 
             program_id)
     }
 
-    pub fn find_staking_token_pda(staking_token_mint_ai: &AccountInfo, program_id: &Pubkey) -> (Pubkey, u8) {
+    pub fn find_staking_token_pda(metadata: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
         Pubkey::find_program_address(
-            &[&staking_token_mint_ai.key.to_bytes(), b"staking-token"], 
+            &[&metadata.to_bytes(), b"staking-token"], 
             program_id)
     }
 
-    pub fn find_rewards_token_pda(rewards_token_mint_ai: &AccountInfo, program_id: &Pubkey) -> (Pubkey, u8) {
+    pub fn find_rewards_token_pda(metadata: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
         Pubkey::find_program_address(
-            &[&rewards_token_mint_ai.key.to_bytes(), b"rewards-token"], 
+            &[&metadata.to_bytes(), b"rewards-token"], 
             program_id)
     }
 
-    pub fn find_user_state_pda(metadata_ai: &AccountInfo, user_ai: &AccountInfo, program_id: &Pubkey) -> (Pubkey, u8) {
+    pub fn find_user_state_pda(metadata: &Pubkey, user: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
         Pubkey::find_program_address(
-            &[&metadata_ai.key.to_bytes(), &user_ai.key.to_bytes(), b"user-state"], 
+            &[&metadata.to_bytes(), &user.to_bytes(), b"user-state"], 
             program_id)
     }
-}
\ No newline at end of file
+
+    pub fn find_receipt_authority_pda(metadata: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[&metadata.to_bytes(), b"receipt-authority"], 
+            program_id)
+    }
+
+    pub fn find_global_config_pda(program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"global-config"], 
+            program_id)
+    }
+}
@@ -1,9 +1,35 @@
-use crate::pda_helper::PdaHelper;
 use solana_program::clock::UnixTimestamp;
-use crate::state::UserStakingState;
+use crate::state::{UserStakingState, UserRewardInfo, RewardInfo, LockTier, UnbondingEntry, PenaltyConfig, PenaltyDestination, GlobalConfig};
 use solana_program::program::invoke_signed;
 use solana_program::program::invoke;
 use crate::state::StakingState;
+use crate::error::StakingError;
+use crate::accounts::{
+    InitAccounts,
+    StakeAccounts,
+    UnstakeAccounts,
+    GetRewardsAccounts,
+    SetRewardRateAccounts,
+    NotifyRewardAmountAccounts,
+    AdminAccounts,
+    AcceptAdminAccounts,
+    AddRewardTokenAccounts,
+    ClaimRewardsAccounts,
+    RewardClaimAccounts,
+    ReceiptAccounts,
+    UserPositionAccounts,
+    UnbondingAccounts,
+    CompoundAccounts,
+    ClosePositionAccounts,
+    ClosePoolAccounts,
+    PoolRewardAccounts,
+    WithdrawExcessRewardsAccounts,
+    FundRewardsAccounts,
+    CreateGlobalConfigAccounts,
+    UpdateGlobalConfigAccounts,
+    TransferPositionAccounts,
+    SetReceiptMintAccounts,
+};
 use solana_program::sysvar::Sysvar;
 use solana_program::sysvar::clock::Clock;
 use borsh::BorshDeserialize;
@@ -12,7 +38,6 @@ use solana_program::{
     pubkey::Pubkey,
     account_info::AccountInfo,
     program_error::ProgramError,
-    account_info::next_account_info,
     program_pack::{Pack},
     msg,
     rent::Rent,
@@ -23,149 +48,235 @@ use spl_token::state::Account;
 pub struct Processor;
 
 impl Processor {
-    const REWARD_RATE: u64 = 100;
+    /// Scale of `reward_per_token_stored` and `reward_per_token_paid`
+    pub const PRECISION: u128 = 1_000_000_000_000;
 
     pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], instruction_data: &[u8]) -> ProgramResult {
         let decoded_data = StakingInstruction::try_from_slice(instruction_data)?;
         match decoded_data {
-            StakingInstruction::Init => {
-                Self::initialize(program_id, accounts)
+            StakingInstruction::Init { reward_rate, reward_duration, max_total_stake, max_stake_per_user, min_stake_amount } => {
+                Self::initialize(program_id, accounts, reward_rate, reward_duration, max_total_stake, max_stake_per_user, min_stake_amount)
             },
             StakingInstruction::Stake { amount } => {
-                Self::stake(program_id, accounts, amount)
+                Self::stake(program_id, accounts, amount, None, None)
             },
             StakingInstruction::Unstake { amount } => {
                 Self::unstake(program_id, accounts, amount)
             },
             StakingInstruction::GetRewards => {
                 Self::get_rewards(program_id, accounts)
+            },
+            StakingInstruction::SetRewardRate { reward_index, reward_rate } => {
+                Self::set_reward_rate(program_id, accounts, reward_index as usize, reward_rate)
+            },
+            StakingInstruction::NotifyRewardAmount { reward_index, amount, duration } => {
+                Self::notify_reward_amount(program_id, accounts, reward_index as usize, amount, duration)
+            },
+            StakingInstruction::ResetPool => {
+                Self::reset_pool(program_id, accounts)
+            },
+            StakingInstruction::ProposeAdmin { new_admin } => {
+                Self::propose_admin(program_id, accounts, new_admin)
+            },
+            StakingInstruction::AcceptAdmin => {
+                Self::accept_admin(program_id, accounts)
+            },
+            StakingInstruction::SetPaused { stake_paused, unstake_paused, claim_paused } => {
+                Self::set_paused(program_id, accounts, stake_paused, unstake_paused, claim_paused)
+            },
+            StakingInstruction::EmergencyWithdraw => {
+                Self::emergency_withdraw(program_id, accounts)
+            },
+            StakingInstruction::AddRewardToken => {
+                Self::add_reward_token(program_id, accounts)
+            },
+            StakingInstruction::ClaimRewards { reward_index } => {
+                Self::claim_rewards(program_id, accounts, reward_index.map(usize::from))
+            },
+            StakingInstruction::StakeLocked { amount, lock_duration } => {
+                Self::stake(program_id, accounts, amount, Some(lock_duration), None)
+            },
+            StakingInstruction::SetLockTiers { lock_tiers } => {
+                Self::set_lock_tiers(program_id, accounts, lock_tiers)
+            },
+            StakingInstruction::SetUnbondingPeriod { unbonding_period } => {
+                Self::set_unbonding_period(program_id, accounts, unbonding_period)
+            },
+            StakingInstruction::RequestUnstake { amount } => {
+                Self::request_unstake(program_id, accounts, amount)
+            },
+            StakingInstruction::WithdrawUnbonded => {
+                Self::withdraw_unbonded(program_id, accounts)
+            },
+            StakingInstruction::CancelUnbonding { index } => {
+                Self::cancel_unbonding(program_id, accounts, index as usize)
+            },
+            StakingInstruction::Compound { reward_index } => {
+                Self::compound(program_id, accounts, reward_index as usize)
+            },
+            StakingInstruction::ClosePosition => {
+                Self::close_position(program_id, accounts)
+            },
+            StakingInstruction::ClosePool => {
+                Self::close_pool(program_id, accounts)
+            },
+            StakingInstruction::WithdrawExcessRewards { reward_index } => {
+                Self::withdraw_excess_rewards(program_id, accounts, reward_index as usize)
+            },
+            StakingInstruction::FundRewards { reward_index, amount, extend_period } => {
+                Self::fund_rewards(program_id, accounts, reward_index as usize, amount, extend_period)
+            },
+            StakingInstruction::SetStakeLimits { max_total_stake, max_stake_per_user, min_stake_amount } => {
+                Self::set_stake_limits(program_id, accounts, max_total_stake, max_stake_per_user, min_stake_amount)
+            },
+            StakingInstruction::SetPenaltyConfig { penalty } => {
+                Self::set_penalty_config(program_id, accounts, penalty)
+            },
+            StakingInstruction::CreateGlobalConfig { fee_bps, fee_recipient } => {
+                Self::create_global_config(program_id, accounts, fee_bps, fee_recipient)
+            },
+            StakingInstruction::UpdateGlobalConfig { authority, fee_bps, fee_recipient } => {
+                Self::update_global_config(program_id, accounts, authority, fee_bps, fee_recipient)
+            },
+            StakingInstruction::StakeFor { beneficiary, amount } => {
+                Self::stake(program_id, accounts, amount, None, Some(beneficiary))
+            },
+            StakingInstruction::SetClaimDelegate { claim_delegate, reward_destination } => {
+                Self::set_claim_delegate(program_id, accounts, claim_delegate, reward_destination)
+            },
+            StakingInstruction::TransferPosition { to, amount } => {
+                Self::transfer_position(program_id, accounts, to, amount)
+            },
+            StakingInstruction::SetReceiptMint => {
+                Self::set_receipt_mint(program_id, accounts)
             }
         }
     }
     
-    fn initialize(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-        let accounts_iter = &mut accounts.iter();
-        let admin_ai = next_account_info(accounts_iter)?;
-        let metadata_pda_ai = next_account_info(accounts_iter)?;
-        let staking_token_mint_ai = next_account_info(accounts_iter)?;
-        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
-        let staking_token_ai = next_account_info(accounts_iter)?;
-        let rewards_token_ai = next_account_info(accounts_iter)?;
-        let system_program = next_account_info(accounts_iter)?;
-        let token_program = next_account_info(accounts_iter)?;
+    fn initialize(
+        program_id: &Pubkey, 
+        accounts: &[AccountInfo], 
+        reward_rate: u64, 
+        reward_duration: u64, 
+        max_total_stake: u64, 
+        max_stake_per_user: u64, 
+        min_stake_amount: u64) -> ProgramResult {
+        let InitAccounts {
+            admin_ai,
+            metadata_ai: metadata_pda_ai,
+            staking_token_mint_ai,
+            rewards_token_mint_ai,
+            escrow_staking_token_ai,
+            escrow_rewards_token_ai,
+            system_program_ai: system_program,
+            metadata_bump: bump_seed,
+        } = InitAccounts::try_from_accounts(program_id, accounts)?;
 
         let clock = Clock::get()?;
+        let reward_duration = UnixTimestamp::try_from(reward_duration).map_err(|_| StakingError::MathOverflow)?;
+        let reward = RewardInfo {
+            mint: *rewards_token_mint_ai.key,
+            escrow: *escrow_rewards_token_ai.key,
+            reward_rate,
+            last_update_timestamp: clock.unix_timestamp,
+            period_finish: clock.unix_timestamp.checked_add(reward_duration).ok_or(StakingError::MathOverflow)?,
+            ..RewardInfo::default()
+        };
 
-        let (metadata_pda, bump_seed) = PdaHelper::find_metadata_pda(staking_token_mint_ai, rewards_token_mint_ai, program_id);
-
-        if *metadata_pda_ai.key != metadata_pda {
-            msg!("Metadata passed: {}", metadata_pda_ai.key);
-            msg!("Metadata computed: {}", metadata_pda);
-            return Err(ProgramError::InvalidAccountData);
-        }
-
-        let in_use = !metadata_pda_ai.try_data_is_empty()?;
-
-        if in_use {
-            let mut staking_state = StakingState::try_from_slice(&metadata_pda_ai.try_borrow_mut_data()?)?;
-            
-            staking_state.admin = *admin_ai.key;
-            staking_state.staking_token_mint = *staking_token_mint_ai.key;
-            staking_state.reward_token_mint = *rewards_token_mint_ai.key;
-            staking_state.total_supply = 0;
-            staking_state.last_update_timestamp = clock.unix_timestamp;
-            staking_state.reward_per_token_stored = 0;
-    
-            staking_state.pack(&mut metadata_pda_ai.try_borrow_mut_data()?);
+        let escrow_rewards_token = Account::unpack(&escrow_rewards_token_ai.try_borrow_data()?)?;
+        Self::check_rewards_funding(&reward, escrow_rewards_token.amount, clock.unix_timestamp)?;
 
-            msg!("Reset metadata values");
-        } else {
-            msg!("Trying to create account");
-    
-            let create_account_ix = solana_program::system_instruction::create_account(
-                &admin_ai.key, 
-                &metadata_pda, 
-                Rent::get()?.minimum_balance(StakingState::LEN),
-                StakingState::LEN as u64, 
-                program_id);
-    
-            invoke_signed(&create_account_ix, &[
-                admin_ai.clone(),
-                metadata_pda_ai.clone(),
-                system_program.clone()
-            ], &[
-                &[
-                    staking_token_mint_ai.key.as_ref(),
-                    rewards_token_mint_ai.key.as_ref(),
-                    b"metadata", 
-                    &[bump_seed]
-                ]
-            ])?;
-            
-            msg!("Metadata pda created: {}", metadata_pda_ai.key);
-    
-            let mut staking_state = StakingState::try_from_slice(&metadata_pda_ai.try_borrow_mut_data()?)?;
-            
-            staking_state.admin = *admin_ai.key;
-            staking_state.staking_token_mint = *staking_token_mint_ai.key;
-            staking_state.reward_token_mint = *rewards_token_mint_ai.key;
-            staking_state.total_supply = 0;
-            staking_state.last_update_timestamp = clock.unix_timestamp;
-            staking_state.reward_per_token_stored = 0;
-    
-            staking_state.pack(&mut metadata_pda_ai.try_borrow_mut_data()?);
-            
-            msg!("Initialized staking with next values: ");
-            msg!("admin: {}", admin_ai.key);
-            msg!("staking token mint pubkey: {}", staking_token_mint_ai.key);
-            msg!("reward token mint pubkey: {}", rewards_token_mint_ai.key);
-            msg!("total staked: {}", 0);
-            msg!("last reward timestamp: {}", clock.unix_timestamp);
-        };
+        msg!("Trying to create account");
 
-        let (staking_token_pda, _nonce) = PdaHelper::find_staking_token_pda(staking_token_mint_ai, program_id);
-        let (rewards_token_pda, _nonce) = PdaHelper::find_rewards_token_pda(rewards_token_mint_ai, program_id);
+        let create_account_ix = solana_program::system_instruction::create_account(
+            admin_ai.key, 
+            metadata_pda_ai.key, 
+            Rent::get()?.minimum_balance(StakingState::LEN),
+            StakingState::LEN as u64, 
+            program_id);
 
-        let staking_token_acc = Account::unpack(&staking_token_ai.try_borrow_data()?)?;
-        let rewards_token_acc = Account::unpack(&rewards_token_ai.try_borrow_data()?)?;
+        invoke_signed(&create_account_ix, &[
+            admin_ai.clone(),
+            metadata_pda_ai.clone(),
+            system_program.clone()
+        ], &[
+            &[
+                staking_token_mint_ai.key.as_ref(),
+                rewards_token_mint_ai.key.as_ref(),
+                admin_ai.key.as_ref(),
+                b"metadata", 
+                &[bump_seed]
+            ]
+        ])?;
+        
+        msg!("Metadata pda created: {}", metadata_pda_ai.key);
 
-        if staking_token_acc.owner != staking_token_pda {
-            msg!("Stake token account must have pda as owner. Current owner {}, pda {}", staking_token_acc.owner, staking_token_pda);
-            return Err(ProgramError::InvalidAccountData);
-        }
+        let mut staking_state = StakingState::unpack(&mut metadata_pda_ai.try_borrow_mut_data()?)?;
+        
+        staking_state.is_initialized = true;
+        staking_state.admin = *admin_ai.key;
+        staking_state.pending_admin = None;
+        staking_state.creator = *admin_ai.key;
+        staking_state.staking_token_mint = *staking_token_mint_ai.key;
+        staking_state.staking_escrow = *escrow_staking_token_ai.key;
+        staking_state.total_supply = 0;
+        staking_state.effective_supply = 0;
+        staking_state.generation = clock.slot;
+        staking_state.lock_tiers = Vec::new();
+        staking_state.unbonding_period = 0;
+        staking_state.max_total_stake = max_total_stake;
+        staking_state.max_stake_per_user = max_stake_per_user;
+        staking_state.min_stake_amount = min_stake_amount;
+        staking_state.penalty = PenaltyConfig::default();
+        staking_state.rewards = vec![reward];
 
-        if rewards_token_acc.owner != rewards_token_pda {
-            msg!("Rewards token account must have pda as owner. Current owner {}, pda {}", rewards_token_acc.owner, rewards_token_pda);
-            return Err(ProgramError::InvalidAccountData);
-        }
+        staking_state.pack(&mut metadata_pda_ai.try_borrow_mut_data()?)?;
+        
+        msg!("Initialized staking with next values: ");
+        msg!("admin: {}", admin_ai.key);
+        msg!("staking token mint pubkey: {}", staking_token_mint_ai.key);
+        msg!("reward token mint pubkey: {}", rewards_token_mint_ai.key);
+        msg!("staking escrow pubkey: {}", escrow_staking_token_ai.key);
+        msg!("rewards escrow pubkey: {}", escrow_rewards_token_ai.key);
+        msg!("total staked: {}", 0);
+        msg!("stake limits. Pool: {}, per user: {}, minimum: {}", max_total_stake, max_stake_per_user, min_stake_amount);
+        msg!("reward rate: {}, period finish: {}", reward_rate, staking_state.rewards[0].period_finish);
+        msg!("last reward timestamp: {}", clock.unix_timestamp);
 
         Ok(())
     }
 
-    fn stake(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
-        let accounts_iter = &mut accounts.iter();
-        let user_ai = next_account_info(accounts_iter)?;
-        let user_staking_token_ai = next_account_info(accounts_iter)?;
-        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
-        let user_state_ai = next_account_info(accounts_iter)?;
-        let metadata_ai = next_account_info(accounts_iter)?;
-        let staking_token_mint_ai = next_account_info(accounts_iter)?;
-        let token_program = next_account_info(accounts_iter)?;
-        let system_program = next_account_info(accounts_iter)?;
-        
-        let (escrow_staking_token_owner_pda, _nonce) = PdaHelper::find_staking_token_pda(staking_token_mint_ai, program_id);
-        let escrow_staking_token = Account::unpack(&escrow_staking_token_ai.try_borrow_data()?)?;
+    fn stake(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, lock_duration: Option<u64>, beneficiary: Option<Pubkey>) -> ProgramResult {
+        let StakeAccounts {
+            user_ai,
+            user_staking_token_ai,
+            escrow_staking_token_ai,
+            user_state_ai,
+            metadata_ai,
+            token_program_ai: token_program,
+            system_program_ai: system_program,
+            user_state_bump: bump_seed,
+            beneficiary,
+            receipt,
+        } = StakeAccounts::try_from_accounts(program_id, accounts, beneficiary)?;
 
-        if escrow_staking_token.owner != escrow_staking_token_owner_pda {
-            msg!("Stake token account must have pda as owner. Current owner {}, pda {}", escrow_staking_token.owner, escrow_staking_token_owner_pda);
-            return Err(ProgramError::InvalidAccountData);
+        let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if state.stake_paused {
+            msg!("Staking is paused");
+            return Err(StakingError::PoolPaused.into());
         }
 
+        let lock_tier = match lock_duration {
+            Some(lock_duration) => Some(state.lock_tier(lock_duration)?),
+            None => None,
+        };
+
         let transafer_ix = spl_token::instruction::transfer(
-            &token_program.key, 
-            &user_staking_token_ai.key,
-            &escrow_staking_token_ai.key, 
-            &user_ai.key, 
+            token_program.key, 
+            user_staking_token_ai.key,
+            escrow_staking_token_ai.key, 
+            user_ai.key, 
             &[], 
             amount)?;
 
@@ -180,18 +291,13 @@ impl Processor {
 
         msg!("Tokens transfered from staker {} to pda {}", user_staking_token_ai.key, escrow_staking_token_ai.key);
 
-        let (user_state_pda, bump_seed) = PdaHelper::find_user_state_pda(metadata_ai, user_ai, program_id);
-
-        msg!("Staker pda: {}", user_state_ai.key);
-        msg!("Staker pda computed: {}", user_state_pda);
-
         if user_state_ai.try_data_is_empty()? {
             let create_acc_ix = solana_program::system_instruction::create_account(
-                &user_ai.key, 
-                &user_state_pda, 
+                user_ai.key, 
+                user_state_ai.key, 
                 Rent::get()?.minimum_balance(UserStakingState::LEN),
                 UserStakingState::LEN as u64, 
-                &program_id);
+                program_id);
     
             invoke_signed(
                 &create_acc_ix,
@@ -201,210 +307,2000 @@ impl Processor {
                     system_program.clone()
                 ], 
                 &[
-                    &[&metadata_ai.key.to_bytes(), &user_ai.key.to_bytes(), b"user-state", &[bump_seed]]
+                    &[&metadata_ai.key.to_bytes(), &beneficiary.to_bytes(), b"user-state", &[bump_seed]]
                 ]
             )?;
+
+            msg!("Staker pda created: {}", user_state_ai.key);
         }
-        
+
+        Self::open_position(metadata_ai, user_state_ai)?;
         Self::update_rewards(metadata_ai, user_state_ai)?;
 
-        let mut user_state = UserStakingState::try_from_slice(&user_state_ai.try_borrow_data()?)?;
-        
-        user_state.balance += amount;
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        let multiplier_bps = match lock_tier {
+            Some(lock_tier) => {
+                let lock_duration = UnixTimestamp::try_from(lock_tier.duration).map_err(|_| StakingError::MathOverflow)?;
+                let lock_end = current_timestamp.checked_add(lock_duration).ok_or(StakingError::MathOverflow)?;
+
+                if lock_end < user_state.lock_end {
+                    msg!("Position is already locked until {}, new lock ends at {}", user_state.lock_end, lock_end);
+                    return Err(StakingError::LockShortened.into());
+                }
+
+                user_state.lock_end = lock_end;
+                msg!("Position locked until {} with multiplier {} bps", lock_end, lock_tier.multiplier_bps);
+                lock_tier.multiplier_bps
+            },
+            None => Self::current_multiplier_bps(&user_state, current_timestamp),
+        };
+
+        Self::check_stake_limits(&metadata, &user_state, amount)?;
+
+        // a deposit of another wallet must not restart the penalty window of the position owner
+        user_state.last_stake_timestamp = if beneficiary == *user_ai.key {
+            current_timestamp
+        } else {
+            Self::deposit_timestamp(&user_state, amount, current_timestamp)?
+        };
 
-        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?);
+        let balance = user_state.balance.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+        Self::set_stake(&mut metadata, &mut user_state, balance, multiplier_bps)?;
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
 
         msg!("Updated staker data at {}", user_state_ai.key);
         
-        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?);
-        metadata.total_supply += amount;
-        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?);
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
 
         msg!("Updated staking metadata at {}", metadata_ai.key);
 
-        msg!("Account {}, staked {} tokens", user_ai.key, amount);
+        Self::mint_receipts(receipt.as_ref(), metadata_ai, amount)?;
+
+        msg!("Account {}, staked {} tokens for {}", user_ai.key, amount, beneficiary);
 
         Ok(())
     }
 
     fn unstake(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
-        let accounts_iter = &mut accounts.iter();
-        let user_ai = next_account_info(accounts_iter)?;
-        let user_staking_token_ai = next_account_info(accounts_iter)?;
-        let user_state_ai = next_account_info(accounts_iter)?;
-        let metadata_ai = next_account_info(accounts_iter)?;
-        let escrow_staking_token_ai = next_account_info(accounts_iter)?;
-        let escrow_staking_token_owner_ai = next_account_info(accounts_iter)?;
-        let staking_token_mint_ai = next_account_info(accounts_iter)?;
-        let token_program_ai = next_account_info(accounts_iter)?;
-
-        if !user_ai.is_signer {
-            return Err(ProgramError::MissingRequiredSignature)
+        let UnstakeAccounts {
+            user_ai,
+            user_staking_token_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_staking_token_ai,
+            escrow_staking_token_owner_ai,
+            staking_token_mint_ai: _,
+            token_program_ai,
+            escrow_staking_token_owner_bump: bump,
+            receipt,
+            penalty_destination_ai,
+        } = UnstakeAccounts::try_from_accounts(program_id, accounts)?;
+
+        let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if state.unstake_paused {
+            msg!("Unstaking is paused");
+            return Err(StakingError::PoolPaused.into());
+        }
+
+        if state.unbonding_period > 0 {
+            msg!("Pool has an unbonding period of {} seconds, use RequestUnstake", state.unbonding_period);
+            return Err(StakingError::UnbondingRequired.into());
         }
+
+        Self::check_unlocked(user_state_ai)?;
         
         Self::update_rewards(metadata_ai, user_state_ai)?;
 
-        let users_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?);
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
 
-        if amount > users_state.balance {
-            msg!("Cannot unstake more than staked. Staked: {}, trying to withdraw: {}", users_state.balance, amount);
-            return Err(ProgramError::InvalidInstructionData);
+        if amount > user_state.balance {
+            msg!("Cannot unstake more than staked. Staked: {}, trying to withdraw: {}", user_state.balance, amount);
+            return Err(StakingError::InsufficientStake.into());
         }
 
-        let (escrow_staking_token_owner, bump) = PdaHelper::find_staking_token_pda(staking_token_mint_ai, program_id);
-        let escrow_staking_token = Account::unpack_from_slice(&mut escrow_staking_token_ai.try_borrow_mut_data()?)?;
-        
-        if escrow_staking_token.owner != escrow_staking_token_owner {
-            msg!("Passed escrow staking owner: {}", escrow_staking_token_owner_ai.key);
-            msg!("Computed escrow staking owner: {}", escrow_staking_token_owner);
-            return Err(ProgramError::InvalidAccountData);
-        }
-        
-        let transfer_ix = spl_token::instruction::transfer(
-            token_program_ai.key, 
-            escrow_staking_token_ai.key, 
-            user_staking_token_ai.key, 
-            &escrow_staking_token_owner, 
-            &[], 
-            amount)?;
+        let penalty = Self::calc_penalty(&metadata.penalty, amount, user_state.last_stake_timestamp, Clock::get()?.unix_timestamp)?;
 
-        invoke_signed(
-            &transfer_ix, 
-            &[
-                escrow_staking_token_ai.clone(),
-                user_staking_token_ai.clone(),
-                escrow_staking_token_owner_ai.clone(),
-                token_program_ai.clone()
-            ],
-            &[
-                &[&staking_token_mint_ai.key.to_bytes(), b"staking-token", &[bump]]
-            ])?;
+        Self::transfer_from_staking_escrow(
+            escrow_staking_token_ai, 
+            user_staking_token_ai, 
+            escrow_staking_token_owner_ai, 
+            metadata_ai.key, 
+            token_program_ai, 
+            bump, 
+            amount.checked_sub(penalty).ok_or(StakingError::MathOverflow)?)?;
+
+        Self::burn_receipts(receipt.as_ref(), user_ai, amount)?;
 
-        msg!("Transfer {} tokens from staking {} account to users {} account", amount, escrow_staking_token_ai.key, user_staking_token_ai.key);
+        let balance = user_state.balance.checked_sub(amount).ok_or(StakingError::InsufficientStake)?;
+        Self::set_stake(&mut metadata, &mut user_state, balance, LockTier::BASE_MULTIPLIER_BPS)?;
+
+        if penalty > 0 {
+            let penalty_destination_ai = penalty_destination_ai.ok_or(StakingError::InvalidPenaltyDestination)?;
+
+            Self::transfer_from_staking_escrow(
+                escrow_staking_token_ai, 
+                penalty_destination_ai, 
+                escrow_staking_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump, 
+                penalty)?;
+
+            Self::settle_penalty(&mut metadata, penalty)?;
+        }
 
-        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?);
-        user_state.balance -= amount;
-        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?);
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
 
         msg!("Staker state updated: {}", user_state_ai.key);
 
-        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?);
-        metadata.total_supply -= amount;
-        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?);
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
 
         msg!("Staking state updated: {}", user_state_ai.key);
 
         Ok(())
     }
 
-    fn get_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-        let accounts_iter = &mut accounts.iter();
-        let user_ai = next_account_info(accounts_iter)?;
-        let user_rewards_token_ai = next_account_info(accounts_iter)?;
-        let user_state_ai = next_account_info(accounts_iter)?;
-        let metadata_ai = next_account_info(accounts_iter)?;
-        let escrow_rewards_token_ai = next_account_info(accounts_iter)?;
-        let escrow_rewards_token_owner_ai = next_account_info(accounts_iter)?;
-        let rewards_token_mint_ai = next_account_info(accounts_iter)?;
-        let token_program_ai = next_account_info(accounts_iter)?;
+    fn emergency_withdraw(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let UnstakeAccounts {
+            user_ai,
+            user_staking_token_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_staking_token_ai,
+            escrow_staking_token_owner_ai,
+            staking_token_mint_ai: _,
+            token_program_ai,
+            escrow_staking_token_owner_bump: bump,
+            receipt,
+            penalty_destination_ai,
+        } = UnstakeAccounts::try_from_accounts(program_id, accounts)?;
+
+        Self::check_unlocked(user_state_ai)?;
+
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let unbonding_amount = Self::total_unbonding(&user_state.unbonding)?;
+
+        if user_state.balance.checked_add(unbonding_amount).ok_or(StakingError::MathOverflow)? == 0 {
+            msg!("Nothing to withdraw for {}", user_ai.key);
+            return Err(StakingError::InsufficientStake.into());
+        }
+
+        // Settle the pool before the supply shrinks so other stakers keep their share, and the
+        // position so rewards accrued since its last checkpoint are released from `owed_rewards`.
+        // The withdrawal must not depend on it, so a failed settlement is only logged.
+        let mut settled_metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut settled_user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let settlement = Self::accrue_rewards(&mut settled_metadata, current_timestamp)
+            .and_then(|()| Self::checkpoint_user(&mut settled_metadata, &mut settled_user_state, current_timestamp));
+
+        match settlement {
+            Ok(()) => {
+                metadata = settled_metadata;
+                user_state = settled_user_state;
+            },
+            Err(err) => msg!("Skipped reward settlement: {}", err),
+        }
+
+        // The staked balance unbonds like a requested unstake, so only matured entries are paid now.
+        // Without an unbonding period it matures at once.
+        let staked = user_state.balance;
+        Self::unbond_balance(&mut metadata, &mut user_state, current_timestamp)?;
+        let (amount, penalty) = Self::take_matured_unbonding(&mut metadata, &mut user_state, current_timestamp)?;
+        let payout = amount.checked_sub(penalty).ok_or(StakingError::MathOverflow)?;
+
+        if payout > 0 {
+            Self::transfer_from_staking_escrow(
+                escrow_staking_token_ai, 
+                user_staking_token_ai, 
+                escrow_staking_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump, 
+                payout)?;
+        }
+
+        Self::burn_receipts(receipt.as_ref(), user_ai, staked)?;
+
+        for (reward_index, (reward, user_reward)) in metadata.rewards.iter_mut().zip(user_state.rewards.iter()).enumerate() {
+            reward.owed_rewards = reward.owed_rewards.saturating_sub(user_reward.rewards);
+            msg!("Account {} forfeited {} tokens of reward stream {}", user_ai.key, user_reward.rewards, reward_index);
+        }
+
+        user_state.rewards = metadata.rewards
+            .iter()
+            .map(|reward| UserRewardInfo {
+                reward_per_token_paid: reward.reward_per_token_stored,
+                ..UserRewardInfo::default()
+            })
+            .collect();
+
+        // redistributed after the position left the pool, so it takes no share of its own penalty
+        if penalty > 0 {
+            let penalty_destination_ai = penalty_destination_ai.ok_or(StakingError::InvalidPenaltyDestination)?;
+
+            Self::transfer_from_staking_escrow(
+                escrow_staking_token_ai, 
+                penalty_destination_ai, 
+                escrow_staking_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump, 
+                penalty)?;
+
+            if let Err(err) = Self::settle_penalty(&mut metadata, penalty) {
+                msg!("Skipped penalty settlement: {}", err);
+            }
+        }
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Emergency withdraw of {} tokens to {}, {} tokens still unbonding", amount, user_staking_token_ai.key, Self::total_unbonding(&user_state.unbonding)?);
 
-        if !user_ai.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
+        Ok(())
+    }
+
+    fn request_unstake(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+        let UnbondingAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            receipt,
+        } = UnbondingAccounts::try_from_accounts(program_id, accounts)?;
+
+        if StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?.unstake_paused {
+            msg!("Unstaking is paused");
+            return Err(StakingError::PoolPaused.into());
         }
 
+        Self::check_unlocked(user_state_ai)?;
+
         Self::update_rewards(metadata_ai, user_state_ai)?;
 
-        let rewards = Self::get_user_rewards(user_state_ai);
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
 
-        let (escrow_rewards_token_owner, bump_seed) = PdaHelper::find_rewards_token_pda(rewards_token_mint_ai, program_id);
+        let entry = Self::request_unbonding(&mut metadata, &mut user_state, amount, Clock::get()?.unix_timestamp)?;
 
-        let transfer_ix = spl_token::instruction::transfer(
-            token_program_ai.key, 
-            escrow_rewards_token_ai.key, 
-            user_rewards_token_ai.key, 
-            &escrow_rewards_token_owner, 
-            &[], 
-            rewards)?;
+        Self::burn_receipts(receipt.as_ref(), user_ai, amount)?;
 
-        invoke_signed(
-            &transfer_ix, 
-            &[
-                escrow_rewards_token_ai.clone(),
-                user_rewards_token_ai.clone(),
-                escrow_rewards_token_owner_ai.clone(),
-                token_program_ai.clone()
-            ],
-            &[
-                &[&rewards_token_mint_ai.key.to_bytes(), b"rewards-token", &[bump_seed]]
-            ])?;
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
 
-        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?);
-        user_state.rewards = 0;
-        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?);
+        msg!("Account {} started unbonding {} tokens, withdrawable at {}", user_ai.key, amount, entry.maturity);
 
         Ok(())
     }
 
-    fn update_rewards(state_ai: &AccountInfo, user_state_ai: &AccountInfo) -> ProgramResult {
-        let mut state = StakingState::unpack(&mut state_ai.try_borrow_mut_data().unwrap());
-        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data().unwrap());
+    fn withdraw_unbonded(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let UnstakeAccounts {
+            user_ai,
+            user_staking_token_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_staking_token_ai,
+            escrow_staking_token_owner_ai,
+            staking_token_mint_ai: _,
+            token_program_ai,
+            escrow_staking_token_owner_bump: bump,
+            receipt: _,
+            penalty_destination_ai,
+        } = UnstakeAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if metadata.unstake_paused {
+            msg!("Unstaking is paused");
+            return Err(StakingError::PoolPaused.into());
+        }
+
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        let (amount, penalty) = Self::take_matured_unbonding(&mut metadata, &mut user_state, current_timestamp)?;
 
-        let rewards_per_token_stored = Self::reward_per_token(&state);
-        let last_update_timestamp = Clock::get().unwrap().unix_timestamp;
+        if amount == 0 {
+            msg!("Account {} has no matured unbonding entries", user_ai.key);
+            return Err(StakingError::NoMaturedUnbonding.into());
+        }
+
+        Self::transfer_from_staking_escrow(
+            escrow_staking_token_ai, 
+            user_staking_token_ai, 
+            escrow_staking_token_owner_ai, 
+            metadata_ai.key, 
+            token_program_ai, 
+            bump, 
+            amount.checked_sub(penalty).ok_or(StakingError::MathOverflow)?)?;
+
+        if penalty > 0 {
+            let penalty_destination_ai = penalty_destination_ai.ok_or(StakingError::InvalidPenaltyDestination)?;
+
+            Self::transfer_from_staking_escrow(
+                escrow_staking_token_ai, 
+                penalty_destination_ai, 
+                escrow_staking_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump, 
+                penalty)?;
 
-        state.reward_per_token_stored = rewards_per_token_stored;
-        state.last_update_timestamp = last_update_timestamp;
+            Self::accrue_rewards(&mut metadata, current_timestamp)?;
+            Self::settle_penalty(&mut metadata, penalty)?;
+        }
 
-        let new_rewards = Self::earned(&state, &user_state);
-        user_state.rewards = new_rewards;
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
 
-        state.pack(&mut state_ai.try_borrow_mut_data()?);
-        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?);
+        msg!("Account {} withdrew {} unbonded tokens", user_ai.key, amount);
 
         Ok(())
     }
 
-    fn get_user_rewards(user_state_ai: &AccountInfo) -> u64 {
-        let user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data().unwrap());
-        return user_state.rewards;
+    fn cancel_unbonding(program_id: &Pubkey, accounts: &[AccountInfo], index: usize) -> ProgramResult {
+        let UnbondingAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            receipt,
+        } = UnbondingAccounts::try_from_accounts(program_id, accounts)?;
+
+        if StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?.stake_paused {
+            msg!("Staking is paused");
+            return Err(StakingError::PoolPaused.into());
+        }
+
+        Self::update_rewards(metadata_ai, user_state_ai)?;
+
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        let entry = Self::cancel_unbonding_entry(&mut metadata, &mut user_state, index, Clock::get()?.unix_timestamp)?;
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Self::mint_receipts(receipt.as_ref(), metadata_ai, entry.amount)?;
+
+        msg!("Account {} restaked {} unbonding tokens", user_ai.key, entry.amount);
+
+        Ok(())
     }
 
-    fn reward_per_token(state: &StakingState) -> u64 {
-        return Self::calc_reward_per_token(state.total_supply, state.reward_per_token_stored, state.last_update_timestamp);
+    fn close_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let ClosePositionAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            destination_ai,
+        } = ClosePositionAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let has_rewards = user_state.rewards.iter().any(|user_reward| user_reward.rewards > 0);
+        // a position of a closed pool only holds rounding dust of its rewards
+        let is_stale = user_state.generation != state.generation;
+
+        // without stake nothing accrues since the last checkpoint, so stored rewards are final
+        if user_state.balance > 0 || !user_state.unbonding.is_empty() || (has_rewards && !is_stale) {
+            msg!("Position {} is not empty. Staked: {}, unbonding entries: {}", user_state_ai.key, user_state.balance, user_state.unbonding.len());
+            return Err(StakingError::PositionNotEmpty.into());
+        }
+
+        let lamports = user_state_ai.lamports();
+        **destination_ai.try_borrow_mut_lamports()? = destination_ai.lamports().checked_add(lamports).ok_or(StakingError::MathOverflow)?;
+        **user_state_ai.try_borrow_mut_lamports()? = 0;
+        user_state_ai.try_borrow_mut_data()?.fill(0);
+
+        if !is_stale {
+            state.open_positions = state.open_positions.checked_sub(1).ok_or(StakingError::MathOverflow)?;
+            state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        }
+
+        msg!("Closed position of {}, {} lamports sent to {}", user_ai.key, lamports, destination_ai.key);
+
+        Ok(())
     }
 
-    fn calc_reward_per_token(total_supply: u64, rewards_per_token_stored: u64, last_update_timestamp: UnixTimestamp) -> u64 {
-        let current_timestamp = Clock::get().unwrap().unix_timestamp; 
-        
-        if total_supply == 0 {
-            return 0;
+    fn get_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let GetRewardsAccounts {
+            user_ai: _,
+            user_rewards_token_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            rewards_token_mint_ai: _,
+            token_program_ai,
+            fee_token_ai,
+            escrow_rewards_token_owner_bump: bump_seed,
+            global_config,
+        } = GetRewardsAccounts::try_from_accounts(program_id, accounts)?;
+
+        if StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?.claim_paused {
+            msg!("Claiming rewards is paused");
+            return Err(StakingError::PoolPaused.into());
         }
 
-        let rewards_per_token = rewards_per_token_stored + (((current_timestamp - last_update_timestamp) as u64) * Self::REWARD_RATE) / total_supply;
-        
-        rewards_per_token
+        Self::update_rewards(metadata_ai, user_state_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let rewards = Self::take_rewards(&mut state, &mut user_state, 0)?;
+        let fee = Self::calc_fee(&global_config, rewards)?;
+        let payout = rewards.checked_sub(fee).ok_or(StakingError::MathOverflow)?;
+
+        Self::transfer_from_rewards_escrow(
+            escrow_rewards_token_ai, 
+            user_rewards_token_ai, 
+            escrow_rewards_token_owner_ai, 
+            metadata_ai.key, 
+            token_program_ai, 
+            bump_seed, 
+            payout)?;
+
+        if fee > 0 {
+            Self::transfer_from_rewards_escrow(
+                escrow_rewards_token_ai, 
+                fee_token_ai, 
+                escrow_rewards_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump_seed, 
+                fee)?;
+        }
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn claim_rewards(program_id: &Pubkey, accounts: &[AccountInfo], reward_index: Option<usize>) -> ProgramResult {
+        let ClaimRewardsAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            token_program_ai,
+            global_config,
+            rewards,
+        } = ClaimRewardsAccounts::try_from_accounts(program_id, accounts, reward_index)?;
+
+        if StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?.claim_paused {
+            msg!("Claiming rewards is paused");
+            return Err(StakingError::PoolPaused.into());
+        }
+
+        Self::update_rewards(metadata_ai, user_state_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        for RewardClaimAccounts {
+            reward_index,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            user_rewards_token_ai,
+            fee_token_ai,
+            escrow_rewards_token_owner_bump: bump,
+        } in rewards {
+            let rewards = Self::take_rewards(&mut state, &mut user_state, reward_index)?;
+
+            if rewards == 0 {
+                continue;
+            }
+
+            let fee = Self::calc_fee(&global_config, rewards)?;
+            let payout = rewards.checked_sub(fee).ok_or(StakingError::MathOverflow)?;
+
+            Self::transfer_from_rewards_escrow(
+                escrow_rewards_token_ai, 
+                user_rewards_token_ai, 
+                escrow_rewards_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump, 
+                payout)?;
+
+            if fee > 0 {
+                Self::transfer_from_rewards_escrow(
+                    escrow_rewards_token_ai, 
+                    fee_token_ai, 
+                    escrow_rewards_token_owner_ai, 
+                    metadata_ai.key, 
+                    token_program_ai, 
+                    bump, 
+                    fee)?;
+            }
+
+            msg!("Account {} claimed {} tokens of reward stream {}, protocol fee {}", user_ai.key, payout, reward_index, fee);
+        }
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn compound(program_id: &Pubkey, accounts: &[AccountInfo], reward_index: usize) -> ProgramResult {
+        let CompoundAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            escrow_staking_token_ai,
+            token_program_ai,
+            fee_token_ai,
+            escrow_rewards_token_owner_bump: bump,
+            global_config,
+            receipt,
+        } = CompoundAccounts::try_from_accounts(program_id, accounts, reward_index)?;
+
+        let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if state.stake_paused || state.claim_paused {
+            msg!("Compounding needs both staking and claiming to be active");
+            return Err(StakingError::PoolPaused.into());
+        }
+
+        Self::update_rewards(metadata_ai, user_state_ai)?;
+
+        let mut metadata = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let rewards = Self::take_rewards(&mut metadata, &mut user_state, reward_index)?;
+
+        if rewards == 0 {
+            msg!("Account {} has no rewards to compound", user_ai.key);
+            return Ok(());
+        }
+
+        let fee = Self::calc_fee(&global_config, rewards)?;
+        let amount = rewards.checked_sub(fee).ok_or(StakingError::MathOverflow)?;
+
+        Self::check_stake_caps(&metadata, &user_state, amount)?;
+
+        Self::transfer_from_rewards_escrow(
+            escrow_rewards_token_ai, 
+            escrow_staking_token_ai, 
+            escrow_rewards_token_owner_ai, 
+            metadata_ai.key, 
+            token_program_ai, 
+            bump, 
+            amount)?;
+
+        if fee > 0 {
+            Self::transfer_from_rewards_escrow(
+                escrow_rewards_token_ai, 
+                fee_token_ai, 
+                escrow_rewards_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                bump, 
+                fee)?;
+        }
+
+        let balance = user_state.balance.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+        let multiplier_bps = Self::current_multiplier_bps(&user_state, Clock::get()?.unix_timestamp);
+        Self::set_stake(&mut metadata, &mut user_state, balance, multiplier_bps)?;
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        metadata.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Self::mint_receipts(receipt.as_ref(), metadata_ai, amount)?;
+
+        msg!("Account {} compounded {} reward tokens into stake", user_ai.key, amount);
+
+        Ok(())
     }
 
-    fn earned(state: &StakingState, user_state: &UserStakingState) -> u64 {
-        return Self::calc_earned(
-            user_state.balance, 
-            state.total_supply, 
-            state.reward_per_token_stored, 
-            state.last_update_timestamp, 
-            user_state.reward_per_token_paid, 
-            user_state.rewards);
+    fn set_reward_rate(program_id: &Pubkey, accounts: &[AccountInfo], reward_index: usize, reward_rate: u64) -> ProgramResult {
+        let SetRewardRateAccounts {
+            admin_ai: _,
+            metadata_ai,
+            escrow_rewards_token_ai,
+        } = SetRewardRateAccounts::try_from_accounts(program_id, accounts, reward_index)?;
+
+        let escrow_rewards_token = Account::unpack(&escrow_rewards_token_ai.try_borrow_data()?)?;
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let reward = state.reward_mut(reward_index)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        let old_reward_rate = reward.reward_rate;
+        reward.reward_rate = reward_rate;
+        Self::check_rewards_funding(reward, escrow_rewards_token.amount, current_timestamp)?;
+
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Reward rate of stream {} changed from {} to {}", reward_index, old_reward_rate, reward_rate);
+
+        Ok(())
     }
 
-    fn calc_earned(
-        stake_amount: u64, 
-        total_supply: u64, 
-        rewards_per_token_stored: u64, 
-        last_update_timestamp: UnixTimestamp, 
-        user_reward_per_token_paid: u64, 
-        user_rewards: u64) -> u64 {
-        let rewards_per_token = Self::calc_reward_per_token(total_supply, rewards_per_token_stored, last_update_timestamp);
-        let earned = stake_amount * ( rewards_per_token - user_reward_per_token_paid) + user_rewards;
+    fn notify_reward_amount(program_id: &Pubkey, accounts: &[AccountInfo], reward_index: usize, amount: u64, duration: u64) -> ProgramResult {
+        let NotifyRewardAmountAccounts {
+            admin_ai,
+            admin_rewards_token_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            token_program_ai,
+        } = NotifyRewardAmountAccounts::try_from_accounts(program_id, accounts, reward_index)?;
+
+        if duration == 0 {
+            msg!("Rewards duration must be positive");
+            return Err(StakingError::InvalidRewardsDuration.into());
+        }
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let transfer_ix = spl_token::instruction::transfer(
+            token_program_ai.key, 
+            admin_rewards_token_ai.key, 
+            escrow_rewards_token_ai.key, 
+            admin_ai.key, 
+            &[], 
+            amount)?;
+
+        invoke(
+            &transfer_ix, 
+            &[
+                admin_rewards_token_ai.clone(),
+                escrow_rewards_token_ai.clone(),
+                admin_ai.clone(),
+                token_program_ai.clone()
+            ])?;
+
+        msg!("Transfer {} reward tokens from admin {} account to escrow {} account", amount, admin_rewards_token_ai.key, escrow_rewards_token_ai.key);
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let reward = state.reward_mut(reward_index)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        let remaining = Self::remaining_period(reward, current_timestamp)?;
+        let leftover = remaining
+            .checked_mul(reward.reward_rate)
+            .and_then(|leftover| leftover.checked_add(reward.unscheduled_funding))
+            .ok_or(StakingError::MathOverflow)?;
+        let reward_rate = amount.checked_add(leftover).ok_or(StakingError::MathOverflow)? / duration;
+
+        let escrow_rewards_token = Account::unpack(&escrow_rewards_token_ai.try_borrow_data()?)?;
+        let duration = UnixTimestamp::try_from(duration).map_err(|_| StakingError::MathOverflow)?;
+
+        reward.reward_rate = reward_rate;
+        reward.total_funded = reward.total_funded.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+        reward.unscheduled_funding = 0;
+        reward.last_update_timestamp = current_timestamp;
+        reward.period_finish = current_timestamp.checked_add(duration).ok_or(StakingError::MathOverflow)?;
+
+        Self::check_rewards_funding(reward, escrow_rewards_token.amount, current_timestamp)?;
+
+        msg!("Rewards period of stream {} started. Rate: {}, period finish: {}", reward_index, reward_rate, reward.period_finish);
+
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn fund_rewards(program_id: &Pubkey, accounts: &[AccountInfo], reward_index: usize, amount: u64, extend_period: bool) -> ProgramResult {
+        let FundRewardsAccounts {
+            funder_ai,
+            funder_rewards_token_ai,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            token_program_ai,
+        } = FundRewardsAccounts::try_from_accounts(program_id, accounts, reward_index)?;
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let transfer_ix = spl_token::instruction::transfer(
+            token_program_ai.key, 
+            funder_rewards_token_ai.key, 
+            escrow_rewards_token_ai.key, 
+            funder_ai.key, 
+            &[], 
+            amount)?;
+
+        invoke(
+            &transfer_ix, 
+            &[
+                funder_rewards_token_ai.clone(),
+                escrow_rewards_token_ai.clone(),
+                funder_ai.clone(),
+                token_program_ai.clone()
+            ])?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let reward = state.reward_mut(reward_index)?;
+        reward.total_funded = reward.total_funded.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+
+        if extend_period {
+            Self::extend_period(reward, amount, Clock::get()?.unix_timestamp)?;
+        } else {
+            reward.unscheduled_funding = reward.unscheduled_funding.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+        }
+
+        msg!("Account {} funded {} tokens to reward stream {}. Total funded: {}, period finish: {}, unscheduled: {}", funder_ai.key, amount, reward_index, reward.total_funded, reward.period_finish, reward.unscheduled_funding);
+
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn reset_pool(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai: _,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if state.total_supply != 0 {
+            msg!("Cannot reset pool with staked tokens. Total staked: {}", state.total_supply);
+            return Err(StakingError::PoolNotEmpty.into());
+        }
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        for reward in state.rewards.iter_mut() {
+            reward.reward_rate = 0;
+            reward.reward_dust = 0;
+            reward.last_update_timestamp = current_timestamp;
+            reward.period_finish = current_timestamp;
+        }
+
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Reset staking pool {}", metadata_ai.key);
+
+        Ok(())
+    }
+
+    fn propose_admin(program_id: &Pubkey, accounts: &[AccountInfo], new_admin: Pubkey) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        state.pending_admin = Some(new_admin);
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Admin {} proposed {} as new admin", admin_ai.key, new_admin);
+
+        Ok(())
+    }
+
+    fn accept_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let AcceptAdminAccounts {
+            pending_admin_ai,
+            metadata_ai,
+        } = AcceptAdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let old_admin = state.admin;
+        state.admin = *pending_admin_ai.key;
+        state.pending_admin = None;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Admin changed from {} to {}", old_admin, pending_admin_ai.key);
+
+        Ok(())
+    }
+
+    fn set_paused(program_id: &Pubkey, accounts: &[AccountInfo], stake_paused: bool, unstake_paused: bool, claim_paused: bool) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai: _,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        state.stake_paused = stake_paused;
+        state.unstake_paused = unstake_paused;
+        state.claim_paused = claim_paused;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Pool paused flags updated. Stake: {}, unstake: {}, claim: {}", stake_paused, unstake_paused, claim_paused);
+
+        Ok(())
+    }
+
+    fn add_reward_token(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let AddRewardTokenAccounts {
+            admin_ai: _,
+            metadata_ai,
+            rewards_token_mint_ai,
+            escrow_rewards_token_ai,
+        } = AddRewardTokenAccounts::try_from_accounts(program_id, accounts)?;
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if state.rewards.iter().any(|reward| reward.mint == *rewards_token_mint_ai.key) {
+            msg!("Reward token {} is already added", rewards_token_mint_ai.key);
+            return Err(StakingError::RewardTokenAlreadyAdded.into());
+        }
+
+        if state.rewards.len() >= StakingState::MAX_REWARD_TOKENS {
+            msg!("Pool already has {} reward tokens", state.rewards.len());
+            return Err(StakingError::TooManyRewardTokens.into());
+        }
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        state.rewards.push(RewardInfo {
+            mint: *rewards_token_mint_ai.key,
+            escrow: *escrow_rewards_token_ai.key,
+            last_update_timestamp: current_timestamp,
+            period_finish: current_timestamp,
+            ..RewardInfo::default()
+        });
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Added reward stream {} for mint {} with escrow {}", state.rewards.len() - 1, rewards_token_mint_ai.key, escrow_rewards_token_ai.key);
+
+        Ok(())
+    }
+
+    fn set_lock_tiers(program_id: &Pubkey, accounts: &[AccountInfo], lock_tiers: Vec<LockTier>) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai: _,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        let has_invalid_tier = lock_tiers
+            .iter()
+            .any(|lock_tier| lock_tier.duration == 0 || lock_tier.multiplier_bps < LockTier::BASE_MULTIPLIER_BPS);
+
+        if has_invalid_tier || lock_tiers.len() > StakingState::MAX_LOCK_TIERS {
+            msg!("Lock tiers must have positive durations and at least base multipliers, up to {} tiers", StakingState::MAX_LOCK_TIERS);
+            return Err(StakingError::InvalidLockTiers.into());
+        }
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        state.lock_tiers = lock_tiers;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Lock tiers updated: {:?}", state.lock_tiers);
+
+        Ok(())
+    }
+
+    fn set_unbonding_period(program_id: &Pubkey, accounts: &[AccountInfo], unbonding_period: u64) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai: _,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let old_unbonding_period = state.unbonding_period;
+        state.unbonding_period = unbonding_period;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Unbonding period changed from {} to {} seconds", old_unbonding_period, unbonding_period);
+
+        Ok(())
+    }
+
+    fn withdraw_excess_rewards(program_id: &Pubkey, accounts: &[AccountInfo], reward_index: usize) -> ProgramResult {
+        let WithdrawExcessRewardsAccounts {
+            admin_ai: _,
+            metadata_ai,
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            destination_ai,
+            token_program_ai,
+            escrow_rewards_token_owner_bump: bump,
+        } = WithdrawExcessRewardsAccounts::try_from_accounts(program_id, accounts, reward_index)?;
+
+        Self::update_reward_per_token(metadata_ai)?;
+
+        let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let reward = state.reward(reward_index)?;
+        let escrow_rewards_token = Account::unpack(&escrow_rewards_token_ai.try_borrow_data()?)?;
+        let excess = Self::excess_rewards(reward, escrow_rewards_token.amount, Clock::get()?.unix_timestamp)?;
+
+        if excess == 0 {
+            msg!("Reward stream {} has no excess rewards. Escrow balance: {}, owed: {}", reward_index, escrow_rewards_token.amount, reward.owed_rewards);
+            return Ok(());
+        }
+
+        Self::transfer_from_rewards_escrow(
+            escrow_rewards_token_ai, 
+            destination_ai, 
+            escrow_rewards_token_owner_ai, 
+            metadata_ai.key, 
+            token_program_ai, 
+            bump, 
+            excess)?;
+
+        msg!("Withdrew {} excess tokens of reward stream {}", excess, reward_index);
+
+        Ok(())
+    }
+
+    fn close_pool(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let ClosePoolAccounts {
+            admin_ai,
+            metadata_ai,
+            escrow_staking_token_ai,
+            escrow_staking_token_owner_ai,
+            token_program_ai,
+            staking_destination_ai,
+            escrow_staking_token_owner_bump,
+            rewards,
+        } = ClosePoolAccounts::try_from_accounts(program_id, accounts)?;
+
+        let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        Self::check_pool_closable(&state)?;
+
+        // without stake or unbonding entries the staking escrow only holds stray transfers
+        let escrow_staking_token = Account::unpack(&escrow_staking_token_ai.try_borrow_data()?)?;
+
+        if escrow_staking_token.amount != 0 {
+            Self::transfer_from_staking_escrow(
+                escrow_staking_token_ai, 
+                staking_destination_ai, 
+                escrow_staking_token_owner_ai, 
+                metadata_ai.key, 
+                token_program_ai, 
+                escrow_staking_token_owner_bump, 
+                escrow_staking_token.amount)?;
+        }
+
+        Self::close_escrow(
+            escrow_staking_token_ai, 
+            admin_ai, 
+            escrow_staking_token_owner_ai, 
+            token_program_ai, 
+            &[&metadata_ai.key.to_bytes(), b"staking-token", &[escrow_staking_token_owner_bump]])?;
+
+        for PoolRewardAccounts {
+            escrow_rewards_token_ai,
+            escrow_rewards_token_owner_ai,
+            rewards_destination_ai,
+            escrow_rewards_token_owner_bump: bump,
+        } in rewards {
+            let escrow_rewards_token = Account::unpack(&escrow_rewards_token_ai.try_borrow_data()?)?;
+
+            if escrow_rewards_token.amount != 0 {
+                Self::transfer_from_rewards_escrow(
+                    escrow_rewards_token_ai, 
+                    rewards_destination_ai, 
+                    escrow_rewards_token_owner_ai, 
+                    metadata_ai.key, 
+                    token_program_ai, 
+                    bump, 
+                    escrow_rewards_token.amount)?;
+            }
+
+            Self::close_escrow(
+                escrow_rewards_token_ai, 
+                admin_ai, 
+                escrow_rewards_token_owner_ai, 
+                token_program_ai, 
+                &[&metadata_ai.key.to_bytes(), b"rewards-token", &[bump]])?;
+        }
+
+        let lamports = metadata_ai.lamports();
+        **admin_ai.try_borrow_mut_lamports()? = admin_ai.lamports().checked_add(lamports).ok_or(StakingError::MathOverflow)?;
+        **metadata_ai.try_borrow_mut_lamports()? = 0;
+        metadata_ai.try_borrow_mut_data()?.fill(0);
+
+        msg!("Closed staking pool {}", metadata_ai.key);
+
+        Ok(())
+    }
+
+    fn close_escrow<'a>(
+        escrow_ai: &AccountInfo<'a>, 
+        destination_ai: &AccountInfo<'a>, 
+        escrow_owner_ai: &AccountInfo<'a>, 
+        token_program_ai: &AccountInfo<'a>, 
+        signer_seeds: &[&[u8]]) -> ProgramResult {
+        let close_ix = spl_token::instruction::close_account(
+            token_program_ai.key, 
+            escrow_ai.key, 
+            destination_ai.key, 
+            escrow_owner_ai.key, 
+            &[])?;
+
+        invoke_signed(
+            &close_ix, 
+            &[
+                escrow_ai.clone(),
+                destination_ai.clone(),
+                escrow_owner_ai.clone(),
+                token_program_ai.clone()
+            ],
+            &[signer_seeds])?;
+
+        msg!("Closed escrow {}", escrow_ai.key);
+
+        Ok(())
+    }
+
+    fn set_stake_limits(program_id: &Pubkey, accounts: &[AccountInfo], max_total_stake: u64, max_stake_per_user: u64, min_stake_amount: u64) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai: _,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        state.max_total_stake = max_total_stake;
+        state.max_stake_per_user = max_stake_per_user;
+        state.min_stake_amount = min_stake_amount;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Stake limits updated. Pool: {}, per user: {}, minimum: {}", max_total_stake, max_stake_per_user, min_stake_amount);
+
+        Ok(())
+    }
+
+    fn set_penalty_config(program_id: &Pubkey, accounts: &[AccountInfo], penalty: PenaltyConfig) -> ProgramResult {
+        let AdminAccounts {
+            admin_ai: _,
+            metadata_ai,
+        } = AdminAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if penalty.penalty_bps > PenaltyConfig::MAX_PENALTY_BPS {
+            msg!("Penalty {} bps is above 100%", penalty.penalty_bps);
+            return Err(StakingError::InvalidPenaltyConfig.into());
+        }
+
+        if penalty.destination == PenaltyDestination::Redistribute && penalty.period > 0 && state.staking_reward_index().is_none() {
+            msg!("Redistributing penalties needs a reward stream paying {}", state.staking_token_mint);
+            return Err(StakingError::InvalidPenaltyConfig.into());
+        }
+
+        state.penalty = penalty;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Penalty config updated: {:?}", penalty);
+
+        Ok(())
+    }
+
+    fn create_global_config(program_id: &Pubkey, accounts: &[AccountInfo], fee_bps: u16, fee_recipient: Pubkey) -> ProgramResult {
+        let CreateGlobalConfigAccounts {
+            authority_ai,
+            global_config_ai,
+            system_program_ai,
+            global_config_bump,
+        } = CreateGlobalConfigAccounts::try_from_accounts(program_id, accounts)?;
+
+        Self::check_fee(fee_bps)?;
+
+        let create_account_ix = solana_program::system_instruction::create_account(
+            authority_ai.key, 
+            global_config_ai.key, 
+            Rent::get()?.minimum_balance(GlobalConfig::LEN),
+            GlobalConfig::LEN as u64, 
+            program_id);
+
+        invoke_signed(&create_account_ix, &[
+            authority_ai.clone(),
+            global_config_ai.clone(),
+            system_program_ai.clone()
+        ], &[
+            &[b"global-config", &[global_config_bump]]
+        ])?;
+
+        let global_config = GlobalConfig {
+            is_initialized: true,
+            authority: *authority_ai.key,
+            fee_bps,
+            fee_recipient,
+        };
+        global_config.pack(&mut global_config_ai.try_borrow_mut_data()?)?;
+
+        msg!("Global config created. Authority: {}, fee: {} bps, fee recipient: {}", authority_ai.key, fee_bps, fee_recipient);
+
+        Ok(())
+    }
+
+    fn update_global_config(program_id: &Pubkey, accounts: &[AccountInfo], authority: Pubkey, fee_bps: u16, fee_recipient: Pubkey) -> ProgramResult {
+        let UpdateGlobalConfigAccounts {
+            authority_ai: _,
+            global_config_ai,
+        } = UpdateGlobalConfigAccounts::try_from_accounts(program_id, accounts)?;
+
+        Self::check_fee(fee_bps)?;
+
+        let mut global_config = GlobalConfig::unpack(&mut global_config_ai.try_borrow_mut_data()?)?;
+        global_config.authority = authority;
+        global_config.fee_bps = fee_bps;
+        global_config.fee_recipient = fee_recipient;
+        global_config.pack(&mut global_config_ai.try_borrow_mut_data()?)?;
+
+        msg!("Global config updated. Authority: {}, fee: {} bps, fee recipient: {}", authority, fee_bps, fee_recipient);
+
+        Ok(())
+    }
+
+    fn set_claim_delegate(program_id: &Pubkey, accounts: &[AccountInfo], claim_delegate: Option<Pubkey>, reward_destination: Option<Pubkey>) -> ProgramResult {
+        let UserPositionAccounts {
+            user_ai,
+            user_state_ai,
+            metadata_ai: _,
+        } = UserPositionAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        user_state.claim_delegate = claim_delegate;
+        user_state.reward_destination = reward_destination;
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        msg!("Account {} set claim delegate {:?} and reward destination {:?}", user_ai.key, claim_delegate, reward_destination);
+
+        Ok(())
+    }
+
+    fn transfer_position(program_id: &Pubkey, accounts: &[AccountInfo], to: Pubkey, amount: u64) -> ProgramResult {
+        let TransferPositionAccounts {
+            user_ai,
+            user_state_ai,
+            recipient_state_ai,
+            metadata_ai,
+            system_program_ai,
+            recipient_state_bump,
+            receipt,
+            recipient_receipt,
+            recipient_signed,
+        } = TransferPositionAccounts::try_from_accounts(program_id, accounts, &to)?;
+
+        let state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if state.stake_paused || state.unstake_paused {
+            msg!("Position transfers are paused");
+            return Err(StakingError::PoolPaused.into());
+        }
+
+        if recipient_state_ai.try_data_is_empty()? {
+            let create_acc_ix = solana_program::system_instruction::create_account(
+                user_ai.key, 
+                recipient_state_ai.key, 
+                Rent::get()?.minimum_balance(UserStakingState::LEN),
+                UserStakingState::LEN as u64, 
+                program_id);
+
+            invoke_signed(
+                &create_acc_ix,
+                &[
+                    user_ai.clone(),
+                    recipient_state_ai.clone(),
+                    system_program_ai.clone()
+                ], 
+                &[
+                    &[&metadata_ai.key.to_bytes(), &to.to_bytes(), b"user-state", &[recipient_state_bump]]
+                ]
+            )?;
+
+            msg!("Staker pda created: {}", recipient_state_ai.key);
+        }
+
+        Self::open_position(metadata_ai, recipient_state_ai)?;
+
+        Self::update_rewards(metadata_ai, user_state_ai)?;
+        Self::update_rewards(metadata_ai, recipient_state_ai)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let mut recipient_state = UserStakingState::unpack(&mut recipient_state_ai.try_borrow_mut_data()?)?;
+
+        if state.max_stake_per_user != 0 && recipient_state.balance.saturating_add(amount) > state.max_stake_per_user {
+            msg!("Transfer {} exceeds the per user cap {}. Recipient staked: {}", amount, state.max_stake_per_user, recipient_state.balance);
+            return Err(StakingError::UserStakeCapExceeded.into());
+        }
+
+        Self::move_stake(&mut state, &mut user_state, &mut recipient_state, amount, recipient_signed)?;
+
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        recipient_state.pack(&mut recipient_state_ai.try_borrow_mut_data()?)?;
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        Self::burn_receipts(receipt.as_ref(), user_ai, amount)?;
+        Self::mint_receipts(recipient_receipt.as_ref(), metadata_ai, amount)?;
+
+        msg!("Account {} transferred {} staked tokens to {}", user_ai.key, amount, to);
+
+        Ok(())
+    }
+
+    fn set_receipt_mint(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let SetReceiptMintAccounts {
+            admin_ai: _,
+            metadata_ai,
+            receipt_mint_ai,
+        } = SetReceiptMintAccounts::try_from_accounts(program_id, accounts)?;
+
+        let mut state = StakingState::unpack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        if let Some(receipt_mint) = state.receipt_mint {
+            msg!("Pool already issues receipts of {}", receipt_mint);
+            return Err(StakingError::AlreadyInitialized.into());
+        }
+
+        // existing stakers hold no receipts, so they could never leave the pool
+        if state.total_supply != 0 {
+            msg!("Cannot enable receipts with staked tokens. Total staked: {}", state.total_supply);
+            return Err(StakingError::PoolNotEmpty.into());
+        }
+
+        state.receipt_mint = Some(*receipt_mint_ai.key);
+        state.pack(&mut metadata_ai.try_borrow_mut_data()?)?;
+
+        msg!("Pool {} issues receipts of {}", metadata_ai.key, receipt_mint_ai.key);
+
+        Ok(())
+    }
+
+    fn check_fee(fee_bps: u16) -> ProgramResult {
+        if fee_bps > GlobalConfig::MAX_FEE_BPS {
+            msg!("Protocol fee {} bps is above 100%", fee_bps);
+            return Err(StakingError::InvalidFee.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks a deposit of `amount` against the minimum stake amount and the stake caps
+    /// A pool can be closed without stake and unbonding entries, once its reward streams owe
+    /// no more than the rounding dust each open position may hold back
+    pub fn check_pool_closable(state: &StakingState) -> ProgramResult {
+        if state.total_supply != 0 || state.total_unbonding != 0 {
+            msg!("Cannot close pool with staked tokens. Total staked: {}, unbonding: {}", state.total_supply, state.total_unbonding);
+            return Err(StakingError::PoolNotEmpty.into());
+        }
+
+        let dust = state.open_positions.saturating_add(1);
+
+        for (reward_index, reward) in state.rewards.iter().enumerate() {
+            if reward.owed_rewards > dust {
+                msg!("Reward stream {} still owes {} tokens to stakers", reward_index, reward.owed_rewards);
+                return Err(StakingError::PoolNotEmpty.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_stake_limits(state: &StakingState, user_state: &UserStakingState, amount: u64) -> ProgramResult {
+        if amount < state.min_stake_amount {
+            msg!("Deposit {} is below the minimum stake amount {}", amount, state.min_stake_amount);
+            return Err(StakingError::StakeBelowMinimum.into());
+        }
+
+        Self::check_stake_caps(state, user_state, amount)
+    }
+
+    /// Checks that staking `amount` more keeps the pool and the position within their caps.
+    /// Compounded rewards are only capped, they may be below the minimum stake amount.
+    pub fn check_stake_caps(state: &StakingState, user_state: &UserStakingState, amount: u64) -> ProgramResult {
+        let total_supply = state.total_supply.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+
+        if state.max_total_stake != 0 && total_supply > state.max_total_stake {
+            msg!("Deposit {} exceeds the pool cap {}. Total staked: {}", amount, state.max_total_stake, state.total_supply);
+            return Err(StakingError::PoolStakeCapExceeded.into());
+        }
+
+        let balance = user_state.balance.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+
+        if state.max_stake_per_user != 0 && balance > state.max_stake_per_user {
+            msg!("Deposit {} exceeds the per user cap {}. Staked: {}", amount, state.max_stake_per_user, user_state.balance);
+            return Err(StakingError::UserStakeCapExceeded.into());
+        }
+
+        Ok(())
+    }
+
+    fn transfer_from_staking_escrow<'a>(
+        escrow_staking_token_ai: &AccountInfo<'a>, 
+        destination_ai: &AccountInfo<'a>, 
+        escrow_staking_token_owner_ai: &AccountInfo<'a>, 
+        metadata: &Pubkey, 
+        token_program_ai: &AccountInfo<'a>, 
+        bump: u8, 
+        amount: u64) -> ProgramResult {
+        let transfer_ix = spl_token::instruction::transfer(
+            token_program_ai.key, 
+            escrow_staking_token_ai.key, 
+            destination_ai.key, 
+            escrow_staking_token_owner_ai.key, 
+            &[], 
+            amount)?;
+
+        invoke_signed(
+            &transfer_ix, 
+            &[
+                escrow_staking_token_ai.clone(),
+                destination_ai.clone(),
+                escrow_staking_token_owner_ai.clone(),
+                token_program_ai.clone()
+            ],
+            &[
+                &[&metadata.to_bytes(), b"staking-token", &[bump]]
+            ])?;
+
+        msg!("Transfer {} tokens from staking {} account to {} account", amount, escrow_staking_token_ai.key, destination_ai.key);
+
+        Ok(())
+    }
+
+    fn transfer_from_rewards_escrow<'a>(
+        escrow_rewards_token_ai: &AccountInfo<'a>, 
+        destination_ai: &AccountInfo<'a>, 
+        escrow_rewards_token_owner_ai: &AccountInfo<'a>, 
+        metadata: &Pubkey, 
+        token_program_ai: &AccountInfo<'a>, 
+        bump: u8, 
+        amount: u64) -> ProgramResult {
+        let transfer_ix = spl_token::instruction::transfer(
+            token_program_ai.key, 
+            escrow_rewards_token_ai.key, 
+            destination_ai.key, 
+            escrow_rewards_token_owner_ai.key, 
+            &[], 
+            amount)?;
+
+        invoke_signed(
+            &transfer_ix, 
+            &[
+                escrow_rewards_token_ai.clone(),
+                destination_ai.clone(),
+                escrow_rewards_token_owner_ai.clone(),
+                token_program_ai.clone()
+            ],
+            &[
+                &[&metadata.to_bytes(), b"rewards-token", &[bump]]
+            ])?;
+
+        msg!("Transfer {} tokens from rewards {} account to {} account", amount, escrow_rewards_token_ai.key, destination_ai.key);
+
+        Ok(())
+    }
+
+    fn mint_receipts<'a>(receipt: Option<&ReceiptAccounts<'_, 'a>>, metadata_ai: &AccountInfo<'a>, amount: u64) -> ProgramResult {
+        let receipt = match receipt {
+            Some(receipt) => receipt,
+            None => return Ok(()),
+        };
+
+        let mint_ix = spl_token::instruction::mint_to(
+            receipt.token_program_ai.key, 
+            receipt.receipt_mint_ai.key, 
+            receipt.user_receipt_token_ai.key, 
+            receipt.receipt_authority_ai.key, 
+            &[], 
+            amount)?;
+
+        invoke_signed(
+            &mint_ix, 
+            &[
+                receipt.receipt_mint_ai.clone(),
+                receipt.user_receipt_token_ai.clone(),
+                receipt.receipt_authority_ai.clone(),
+                receipt.token_program_ai.clone()
+            ],
+            &[
+                &[&metadata_ai.key.to_bytes(), b"receipt-authority", &[receipt.receipt_authority_bump]]
+            ])?;
+
+        msg!("Minted {} receipt tokens to {}", amount, receipt.user_receipt_token_ai.key);
+
+        Ok(())
+    }
+
+    fn burn_receipts<'a>(receipt: Option<&ReceiptAccounts<'_, 'a>>, user_ai: &AccountInfo<'a>, amount: u64) -> ProgramResult {
+        let receipt = match receipt {
+            Some(receipt) => receipt,
+            None => return Ok(()),
+        };
+
+        let burn_ix = spl_token::instruction::burn(
+            receipt.token_program_ai.key, 
+            receipt.user_receipt_token_ai.key, 
+            receipt.receipt_mint_ai.key, 
+            user_ai.key, 
+            &[], 
+            amount)?;
+
+        invoke(
+            &burn_ix, 
+            &[
+                receipt.user_receipt_token_ai.clone(),
+                receipt.receipt_mint_ai.clone(),
+                user_ai.clone(),
+                receipt.token_program_ai.clone()
+            ])?;
+
+        msg!("Burned {} receipt tokens from {}", amount, receipt.user_receipt_token_ai.key);
+
+        Ok(())
+    }
+
+    /// Counts a new position, or one left over from a closed pool, as opened in this pool.
+    /// A leftover position holds no stake, so it starts over empty.
+    fn open_position(state_ai: &AccountInfo, user_state_ai: &AccountInfo) -> ProgramResult {
+        let mut state = StakingState::unpack(&mut state_ai.try_borrow_mut_data()?)?;
+        let user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        if user_state.generation == state.generation {
+            return Ok(());
+        }
+
+        UserStakingState {
+            generation: state.generation,
+            ..UserStakingState::default()
+        }.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        state.open_positions = state.open_positions.checked_add(1).ok_or(StakingError::MathOverflow)?;
+        state.pack(&mut state_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn update_reward_per_token(state_ai: &AccountInfo) -> ProgramResult {
+        let mut state = StakingState::unpack(&mut state_ai.try_borrow_mut_data()?)?;
+
+        Self::accrue_rewards(&mut state, Clock::get()?.unix_timestamp)?;
+
+        state.pack(&mut state_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn check_unlocked(user_state_ai: &AccountInfo) -> ProgramResult {
+        let user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        if Clock::get()?.unix_timestamp < user_state.lock_end {
+            msg!("Stake is locked until {}", user_state.lock_end);
+            return Err(StakingError::StakeLocked.into());
+        }
+
+        Ok(())
+    }
+
+    fn update_rewards(state_ai: &AccountInfo, user_state_ai: &AccountInfo) -> ProgramResult {
+        let mut state = StakingState::unpack(&mut state_ai.try_borrow_mut_data()?)?;
+        let mut user_state = UserStakingState::unpack(&mut user_state_ai.try_borrow_mut_data()?)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+
+        Self::accrue_rewards(&mut state, current_timestamp)?;
+        Self::checkpoint_user(&mut state, &mut user_state, current_timestamp)?;
+
+        state.pack(&mut state_ai.try_borrow_mut_data()?)?;
+        user_state.pack(&mut user_state_ai.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    /// Moves every reward stream accumulator up to `current_timestamp` (clamped at its
+    /// `period_finish`). While the pool is fully paused the accumulators are frozen and
+    /// the rest of each rewards period is shifted forward instead.
+    pub fn accrue_rewards(state: &mut StakingState, current_timestamp: UnixTimestamp) -> ProgramResult {
+        if state.is_fully_paused() {
+            for reward in state.rewards.iter_mut() {
+                if reward.period_finish > reward.last_update_timestamp {
+                    let remaining = reward.period_finish.checked_sub(reward.last_update_timestamp).ok_or(StakingError::MathOverflow)?;
+                    reward.last_update_timestamp = current_timestamp;
+                    reward.period_finish = current_timestamp.checked_add(remaining).ok_or(StakingError::MathOverflow)?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        for reward in state.rewards.iter_mut() {
+            let (reward_per_token_stored, reward_dust) = Self::reward_per_token(state.effective_supply, reward, current_timestamp)?;
+            let last_time_reward_applicable = Self::last_time_reward_applicable(reward, current_timestamp);
+
+            if state.effective_supply > 0 && last_time_reward_applicable > reward.last_update_timestamp {
+                reward.owed_rewards = last_time_reward_applicable
+                    .checked_sub(reward.last_update_timestamp)
+                    .and_then(|elapsed| u64::try_from(elapsed).ok())
+                    .and_then(|elapsed| elapsed.checked_mul(reward.reward_rate))
+                    .and_then(|distributed| distributed.checked_add(reward.owed_rewards))
+                    .ok_or(StakingError::MathOverflow)?;
+            }
+
+            reward.reward_per_token_stored = reward_per_token_stored;
+            reward.reward_dust = reward_dust;
+            reward.last_update_timestamp = last_time_reward_applicable;
+        }
+
+        Ok(())
+    }
+
+    /// Credits the user with rewards of every stream earned since the last checkpoint and
+    /// drops the boost of an expired lock. The boost is credited only up to `lock_end`: the
+    /// boosted share earned after it is redistributed to all stakers once the boost is dropped.
+    /// Must run after `accrue_rewards` and before the user balance changes.
+    pub fn checkpoint_user(state: &mut StakingState, user_state: &mut UserStakingState, current_timestamp: UnixTimestamp) -> ProgramResult {
+        user_state.rewards.resize(state.rewards.len(), UserRewardInfo::default());
+
+        let mut forfeited_rewards = Vec::with_capacity(state.rewards.len());
+        for (reward_index, reward) in state.rewards.iter().enumerate() {
+            let (rewards, rewards_dust) = Self::earned(user_state.effective_balance, reward, &user_state.rewards[reward_index])?;
+            let new_rewards = rewards.checked_sub(user_state.rewards[reward_index].rewards).ok_or(StakingError::MathOverflow)?;
+            let forfeited = Self::expired_boost_rewards(user_state, reward.last_update_timestamp, new_rewards)?;
+
+            let user_reward = &mut user_state.rewards[reward_index];
+            user_reward.rewards = rewards.checked_sub(forfeited).ok_or(StakingError::MathOverflow)?;
+            user_reward.rewards_dust = rewards_dust;
+            user_reward.reward_per_token_paid = reward.reward_per_token_stored;
+            forfeited_rewards.push(forfeited);
+        }
+
+        user_state.last_checkpoint_timestamp = current_timestamp;
+        if user_state.lock_end == 0 || current_timestamp < user_state.lock_end {
+            return Ok(());
+        }
+
+        user_state.lock_end = 0;
+        Self::set_stake(state, user_state, user_state.balance, LockTier::BASE_MULTIPLIER_BPS)?;
+
+        for (reward_index, forfeited) in forfeited_rewards.into_iter().enumerate() {
+            if forfeited > 0 {
+                let reward = state.reward_mut(reward_index)?;
+                reward.owed_rewards = reward.owed_rewards.checked_sub(forfeited).ok_or(StakingError::MathOverflow)?;
+                Self::redistribute(state, reward_index, forfeited)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Part of `earned` rewards paid for the boost of an expired lock after `lock_end`. Rewards
+    /// since the last checkpoint up to `last_update_timestamp` of the stream are split by time.
+    fn expired_boost_rewards(user_state: &UserStakingState, last_update_timestamp: UnixTimestamp, earned: u64) -> Result<u64, ProgramError> {
+        if user_state.lock_end == 0 
+            || user_state.lock_end >= last_update_timestamp 
+            || user_state.last_checkpoint_timestamp >= last_update_timestamp 
+            || user_state.effective_balance == 0 {
+            return Ok(0);
+        }
+
+        let boost_rewards = earned as u128 * user_state.effective_balance.saturating_sub(user_state.balance) as u128 
+            / user_state.effective_balance as u128;
+        let expired = last_update_timestamp
+            .checked_sub(user_state.lock_end.max(user_state.last_checkpoint_timestamp))
+            .ok_or(StakingError::MathOverflow)?;
+        let elapsed = last_update_timestamp
+            .checked_sub(user_state.last_checkpoint_timestamp)
+            .ok_or(StakingError::MathOverflow)?;
+
+        u64::try_from(boost_rewards * expired as u128 / elapsed as u128).map_err(|_| StakingError::MathOverflow.into())
+    }
+
+    /// Sets the user balance and multiplier and moves pool supplies by the difference.
+    /// Must run after `checkpoint_user`.
+    pub fn set_stake(state: &mut StakingState, user_state: &mut UserStakingState, balance: u64, multiplier_bps: u16) -> ProgramResult {
+        let effective_balance = u64::try_from(balance as u128 * multiplier_bps as u128 / LockTier::BASE_MULTIPLIER_BPS as u128)
+            .map_err(|_| StakingError::MathOverflow)?;
+
+        state.total_supply = state.total_supply
+            .checked_sub(user_state.balance)
+            .and_then(|total_supply| total_supply.checked_add(balance))
+            .ok_or(StakingError::MathOverflow)?;
+        state.effective_supply = state.effective_supply
+            .checked_sub(user_state.effective_balance)
+            .and_then(|effective_supply| effective_supply.checked_add(effective_balance))
+            .ok_or(StakingError::MathOverflow)?;
+
+        user_state.balance = balance;
+        user_state.effective_balance = effective_balance;
+        user_state.multiplier_bps = multiplier_bps;
+
+        Ok(())
+    }
+
+    /// Zeroes the user rewards of a stream and returns them. The caller pays them out.
+    pub fn take_rewards(state: &mut StakingState, user_state: &mut UserStakingState, reward_index: usize) -> Result<u64, ProgramError> {
+        let reward = state.reward_mut(reward_index)?;
+        let user_reward = user_state.rewards.get_mut(reward_index).ok_or(StakingError::InvalidRewardIndex)?;
+        let rewards = user_reward.rewards;
+
+        user_reward.rewards = 0;
+        reward.owed_rewards = reward.owed_rewards.saturating_sub(rewards);
+
+        Ok(rewards)
+    }
+
+    /// Protocol fee skimmed from a rewards payout
+    pub fn calc_fee(global_config: &GlobalConfig, rewards: u64) -> Result<u64, ProgramError> {
+        let fee = rewards as u128 * global_config.fee_bps as u128 / GlobalConfig::MAX_FEE_BPS as u128;
+
+        u64::try_from(fee).map_err(|_| StakingError::MathOverflow.into())
+    }
+
+    /// Escrow balance which is neither owed to stakers, needed for the rest of the period
+    /// nor funding waiting to be scheduled
+    pub fn excess_rewards(reward: &RewardInfo, escrow_balance: u64, current_timestamp: UnixTimestamp) -> Result<u64, ProgramError> {
+        let committed = Self::committed_rewards(reward, current_timestamp)?;
+
+        Ok(escrow_balance.saturating_sub(committed))
+    }
+
+    /// Checks the escrow pays the rest of the rewards period on top of owed rewards and unscheduled funding
+    pub fn check_rewards_funding(reward: &RewardInfo, escrow_balance: u64, current_timestamp: UnixTimestamp) -> ProgramResult {
+        let committed = Self::committed_rewards(reward, current_timestamp)?;
+
+        if committed > escrow_balance {
+            msg!("Reward rate {} is not funded until period finish {}. Committed: {}, escrow balance: {}", reward.reward_rate, reward.period_finish, committed, escrow_balance);
+            return Err(StakingError::InsufficientRewardsFunding.into());
+        }
+
+        Ok(())
+    }
+
+    /// Rewards the escrow must keep for the rest of the period, stakers and a later period
+    fn committed_rewards(reward: &RewardInfo, current_timestamp: UnixTimestamp) -> Result<u64, ProgramError> {
+        Self::remaining_period(reward, current_timestamp)?
+            .checked_mul(reward.reward_rate)
+            .and_then(|committed| committed.checked_add(reward.owed_rewards))
+            .and_then(|committed| committed.checked_add(reward.unscheduled_funding))
+            .ok_or_else(|| StakingError::MathOverflow.into())
+    }
+
+    /// Prolongs an active rewards period by the whole seconds `amount` pays for at the
+    /// current rate. The rest, or the whole amount for a finished or rateless period,
+    /// is left in `unscheduled_funding`.
+    pub fn extend_period(reward: &mut RewardInfo, amount: u64, current_timestamp: UnixTimestamp) -> ProgramResult {
+        let unscheduled = if reward.reward_rate == 0 || reward.period_finish <= current_timestamp {
+            amount
+        } else {
+            let extension = UnixTimestamp::try_from(amount / reward.reward_rate).map_err(|_| StakingError::MathOverflow)?;
+            reward.period_finish = reward.period_finish.checked_add(extension).ok_or(StakingError::MathOverflow)?;
+            amount % reward.reward_rate
+        };
+
+        reward.unscheduled_funding = reward.unscheduled_funding.checked_add(unscheduled).ok_or(StakingError::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// Penalty of unstaking `amount` at `current_timestamp` after the last stake at `last_stake_timestamp`
+    pub fn calc_penalty(penalty: &PenaltyConfig, amount: u64, last_stake_timestamp: UnixTimestamp, current_timestamp: UnixTimestamp) -> Result<u64, ProgramError> {
+        let elapsed = current_timestamp.saturating_sub(last_stake_timestamp).max(0) as u64;
+
+        if elapsed >= penalty.period {
+            return Ok(0);
+        }
+
+        let full_penalty = amount as u128 * penalty.penalty_bps as u128 / PenaltyConfig::MAX_PENALTY_BPS as u128;
+        let penalty = if penalty.linear_decay {
+            full_penalty * penalty.period.checked_sub(elapsed).ok_or(StakingError::MathOverflow)? as u128 / penalty.period as u128
+        } else {
+            full_penalty
+        };
+
+        u64::try_from(penalty).map_err(|_| StakingError::MathOverflow.into())
+    }
+
+    /// Penalty window start of a position after a deposit of `amount` at `current_timestamp`,
+    /// weighted by the stake already in the position
+    pub fn deposit_timestamp(user_state: &UserStakingState, amount: u64, current_timestamp: UnixTimestamp) -> Result<UnixTimestamp, ProgramError> {
+        let balance = user_state.balance as i128;
+        let total = balance.checked_add(amount as i128).ok_or(StakingError::MathOverflow)?;
+
+        if total == 0 {
+            return Ok(current_timestamp);
+        }
+
+        let weighted = (user_state.last_stake_timestamp as i128)
+            .checked_mul(balance)
+            .and_then(|weighted| weighted.checked_add((current_timestamp as i128).checked_mul(amount as i128)?))
+            .ok_or(StakingError::MathOverflow)?;
+
+        UnixTimestamp::try_from(weighted / total).map_err(|_| StakingError::MathOverflow.into())
+    }
+
+    /// Distributes `amount` of a stream to current stakers at once. Must run after `accrue_rewards`.
+    /// Without stakers the amount is left in the escrow as excess rewards.
+    pub fn redistribute(state: &mut StakingState, reward_index: usize, amount: u64) -> ProgramResult {
+        let effective_supply = state.effective_supply;
+        let reward = state.reward_mut(reward_index)?;
+
+        if effective_supply == 0 {
+            return Ok(());
+        }
+
+        let scaled_amount = (amount as u128)
+            .checked_mul(Self::PRECISION)
+            .and_then(|scaled_amount| scaled_amount.checked_add(reward.reward_dust))
+            .ok_or(StakingError::MathOverflow)?;
+
+        reward.reward_per_token_stored = reward.reward_per_token_stored
+            .checked_add(scaled_amount / effective_supply as u128)
+            .ok_or(StakingError::MathOverflow)?;
+        reward.reward_dust = scaled_amount % effective_supply as u128;
+        reward.owed_rewards = reward.owed_rewards.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// Moves `amount` of stake and the same share of unclaimed rewards from one position to another.
+    /// Both positions must be checkpointed and have their expired locks released first. The recipient
+    /// takes the later lock end and penalty window, which needs its signature when it holds stake.
+    pub fn move_stake(
+        state: &mut StakingState, 
+        from: &mut UserStakingState, 
+        to: &mut UserStakingState, 
+        amount: u64, 
+        recipient_signed: bool) -> ProgramResult {
+        if amount > from.balance {
+            msg!("Cannot transfer more than staked. Staked: {}, trying to transfer: {}", from.balance, amount);
+            return Err(StakingError::InsufficientStake.into());
+        }
+
+        if to.balance > 0 && to.multiplier_bps != from.multiplier_bps {
+            msg!("Recipient multiplier {} bps differs from the transferred {} bps", to.multiplier_bps, from.multiplier_bps);
+            return Err(StakingError::LockMismatch.into());
+        }
+
+        let extends_windows = from.lock_end > to.lock_end || from.last_stake_timestamp > to.last_stake_timestamp;
+        if to.balance > 0 && extends_windows && !recipient_signed {
+            msg!("Transfer extends the lock end {} and penalty window {} of the recipient", to.lock_end, to.last_stake_timestamp);
+            return Err(StakingError::RecipientSignatureRequired.into());
+        }
+
+        if from.balance > 0 {
+            for (from_reward, to_reward) in from.rewards.iter_mut().zip(to.rewards.iter_mut()) {
+                let moved = u64::try_from(from_reward.rewards as u128 * amount as u128 / from.balance as u128)
+                    .map_err(|_| StakingError::MathOverflow)?;
+
+                from_reward.rewards = from_reward.rewards.checked_sub(moved).ok_or(StakingError::MathOverflow)?;
+                to_reward.rewards = to_reward.rewards.checked_add(moved).ok_or(StakingError::MathOverflow)?;
+            }
+        }
+
+        to.lock_end = to.lock_end.max(from.lock_end);
+        to.last_stake_timestamp = to.last_stake_timestamp.max(from.last_stake_timestamp);
+
+        let multiplier_bps = from.multiplier_bps;
+        let from_balance = from.balance.checked_sub(amount).ok_or(StakingError::MathOverflow)?;
+        let to_balance = to.balance.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+        Self::set_stake(state, from, from_balance, multiplier_bps)?;
+        Self::set_stake(state, to, to_balance, multiplier_bps)
+    }
+
+    /// Moves `amount` of stake into a new unbonding entry, which withholds the early unstake penalty.
+    /// The position must be checkpointed first.
+    pub fn request_unbonding(
+        state: &mut StakingState, 
+        user_state: &mut UserStakingState, 
+        amount: u64, 
+        current_timestamp: UnixTimestamp) -> Result<UnbondingEntry, ProgramError> {
+        if amount > user_state.balance {
+            msg!("Cannot unstake more than staked. Staked: {}, trying to withdraw: {}", user_state.balance, amount);
+            return Err(StakingError::InsufficientStake.into());
+        }
+
+        if user_state.unbonding.len() >= UserStakingState::MAX_UNBONDING_ENTRIES {
+            msg!("Position already has {} pending unbonding entries", user_state.unbonding.len());
+            return Err(StakingError::TooManyUnbondingEntries.into());
+        }
+
+        let entry = Self::unbonding_entry(state, user_state, amount, current_timestamp)?;
+        let balance = user_state.balance.checked_sub(amount).ok_or(StakingError::MathOverflow)?;
+        Self::set_stake(state, user_state, balance, LockTier::BASE_MULTIPLIER_BPS)?;
+        state.total_unbonding = state.total_unbonding.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+        user_state.unbonding.push(entry.clone());
+
+        Ok(entry)
+    }
+
+    /// Moves the whole balance into an unbonding entry for `EmergencyWithdraw`.
+    /// A position without free slots has it merged into its last entry.
+    pub fn unbond_balance(state: &mut StakingState, user_state: &mut UserStakingState, current_timestamp: UnixTimestamp) -> ProgramResult {
+        if user_state.balance == 0 {
+            return Ok(());
+        }
+
+        let amount = user_state.balance;
+        let entry = Self::unbonding_entry(state, user_state, amount, current_timestamp)?;
+        Self::push_unbonding(&mut user_state.unbonding, entry)?;
+        Self::set_stake(state, user_state, 0, LockTier::BASE_MULTIPLIER_BPS)?;
+        state.total_unbonding = state.total_unbonding.checked_add(amount).ok_or(StakingError::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// Removes matured unbonding entries and returns their amount together with the withheld penalty
+    pub fn take_matured_unbonding(
+        state: &mut StakingState, 
+        user_state: &mut UserStakingState, 
+        current_timestamp: UnixTimestamp) -> Result<(u64, u64), ProgramError> {
+        let (matured, pending): (Vec<UnbondingEntry>, Vec<UnbondingEntry>) = user_state.unbonding
+            .drain(..)
+            .partition(|entry| entry.maturity <= current_timestamp);
+        user_state.unbonding = pending;
+
+        let amount = Self::total_unbonding(&matured)?;
+        let penalty = Self::total_unbonding_penalty(&matured)?;
+        state.total_unbonding = state.total_unbonding.checked_sub(amount).ok_or(StakingError::MathOverflow)?;
+
+        Ok((amount, penalty))
+    }
+
+    /// Stakes a pending unbonding entry again, dropping its penalty. The position must be checkpointed first.
+    pub fn cancel_unbonding_entry(
+        state: &mut StakingState, 
+        user_state: &mut UserStakingState, 
+        index: usize, 
+        current_timestamp: UnixTimestamp) -> Result<UnbondingEntry, ProgramError> {
+        if index >= user_state.unbonding.len() {
+            msg!("Unbonding entry {} does not exist", index);
+            return Err(StakingError::InvalidUnbondingIndex.into());
+        }
+
+        Self::check_stake_caps(state, user_state, user_state.unbonding[index].amount)?;
+        let entry = user_state.unbonding.remove(index);
+
+        let balance = user_state.balance.checked_add(entry.amount).ok_or(StakingError::MathOverflow)?;
+        let multiplier_bps = Self::current_multiplier_bps(user_state, current_timestamp);
+        Self::set_stake(state, user_state, balance, multiplier_bps)?;
+        state.total_unbonding = state.total_unbonding.checked_sub(entry.amount).ok_or(StakingError::MathOverflow)?;
+
+        Ok(entry)
+    }
+
+    /// Adds an unbonding entry, merging it into the last one when all slots are taken
+    /// so a position can always leave the pool
+    fn push_unbonding(entries: &mut Vec<UnbondingEntry>, entry: UnbondingEntry) -> ProgramResult {
+        if entries.len() < UserStakingState::MAX_UNBONDING_ENTRIES {
+            entries.push(entry);
+            return Ok(());
+        }
+
+        let last = entries.last_mut().ok_or(StakingError::TooManyUnbondingEntries)?;
+        last.amount = last.amount.checked_add(entry.amount).ok_or(StakingError::MathOverflow)?;
+        last.penalty = last.penalty.checked_add(entry.penalty).ok_or(StakingError::MathOverflow)?;
+        last.maturity = last.maturity.max(entry.maturity);
+
+        Ok(())
+    }
+
+    /// Unbonding entry of `amount` leaving the position at `current_timestamp`
+    fn unbonding_entry(state: &StakingState, user_state: &UserStakingState, amount: u64, current_timestamp: UnixTimestamp) -> Result<UnbondingEntry, ProgramError> {
+        let unbonding_period = UnixTimestamp::try_from(state.unbonding_period).map_err(|_| StakingError::MathOverflow)?;
+
+        Ok(UnbondingEntry {
+            amount,
+            maturity: current_timestamp.checked_add(unbonding_period).ok_or(StakingError::MathOverflow)?,
+            penalty: Self::calc_penalty(&state.penalty, amount, user_state.last_stake_timestamp, current_timestamp)?,
+        })
+    }
+
+    /// Multiplier a position keeps when its balance changes without a new lock
+    fn current_multiplier_bps(user_state: &UserStakingState, current_timestamp: UnixTimestamp) -> u16 {
+        if user_state.lock_end > current_timestamp {
+            user_state.multiplier_bps
+        } else {
+            LockTier::BASE_MULTIPLIER_BPS
+        }
+    }
+
+    fn total_unbonding(entries: &[UnbondingEntry]) -> Result<u64, ProgramError> {
+        entries
+            .iter()
+            .try_fold(0u64, |total, entry| total.checked_add(entry.amount))
+            .ok_or_else(|| StakingError::MathOverflow.into())
+    }
+
+    fn total_unbonding_penalty(entries: &[UnbondingEntry]) -> Result<u64, ProgramError> {
+        entries
+            .iter()
+            .try_fold(0u64, |total, entry| total.checked_add(entry.penalty))
+            .ok_or_else(|| StakingError::MathOverflow.into())
+    }
+
+    /// Accounts an early unstake penalty already transferred to the pool penalty destination.
+    /// Redistribution must run after `accrue_rewards`.
+    fn settle_penalty(metadata: &mut StakingState, penalty: u64) -> ProgramResult {
+        if metadata.penalty.destination == PenaltyDestination::Redistribute {
+            let reward_index = metadata.staking_reward_index().ok_or(StakingError::InvalidPenaltyConfig)?;
+            Self::redistribute(metadata, reward_index, penalty)?;
+        }
+
+        msg!("Charged early unstake penalty of {} tokens", penalty);
+
+        Ok(())
+    }
+
+    fn remaining_period(reward: &RewardInfo, current_timestamp: UnixTimestamp) -> Result<u64, ProgramError> {
+        if current_timestamp >= reward.period_finish {
+            return Ok(0);
+        }
+
+        reward.period_finish
+            .checked_sub(current_timestamp)
+            .and_then(|remaining| u64::try_from(remaining).ok())
+            .ok_or_else(|| StakingError::MathOverflow.into())
+    }
+
+    fn last_time_reward_applicable(reward: &RewardInfo, current_timestamp: UnixTimestamp) -> UnixTimestamp {
+        current_timestamp.min(reward.period_finish)
+    }
+
+    fn reward_per_token(effective_supply: u64, reward: &RewardInfo, current_timestamp: UnixTimestamp) -> Result<(u128, u128), ProgramError> {
+        Self::calc_reward_per_token(
+            effective_supply, 
+            reward.reward_rate, 
+            reward.reward_per_token_stored, 
+            reward.reward_dust, 
+            reward.last_update_timestamp, 
+            Self::last_time_reward_applicable(reward, current_timestamp))
+    }
+
+    /// Returns the new accumulator value together with the scaled rewards which could not be
+    /// distributed because of rounding. That dust is carried into the next accrual.
+    fn calc_reward_per_token(
+        effective_supply: u64, 
+        reward_rate: u64, 
+        rewards_per_token_stored: u128, 
+        reward_dust: u128, 
+        last_update_timestamp: UnixTimestamp, 
+        last_time_reward_applicable: UnixTimestamp) -> Result<(u128, u128), ProgramError> {
+        if effective_supply == 0 || last_time_reward_applicable <= last_update_timestamp {
+            return Ok((rewards_per_token_stored, reward_dust));
+        }
+
+        let elapsed = last_time_reward_applicable.checked_sub(last_update_timestamp).ok_or(StakingError::MathOverflow)?;
+        let scaled_rewards = (elapsed as u128)
+            .checked_mul(reward_rate as u128)
+            .and_then(|rewards| rewards.checked_mul(Self::PRECISION))
+            .and_then(|rewards| rewards.checked_add(reward_dust))
+            .ok_or(StakingError::MathOverflow)?;
+        let rewards_per_token = rewards_per_token_stored
+            .checked_add(scaled_rewards / effective_supply as u128)
+            .ok_or(StakingError::MathOverflow)?;
+
+        Ok((rewards_per_token, scaled_rewards % effective_supply as u128))
+    }
+
+    fn earned(effective_balance: u64, reward: &RewardInfo, user_reward: &UserRewardInfo) -> Result<(u64, u128), ProgramError> {
+        Self::calc_earned(
+            effective_balance, 
+            reward.reward_per_token_stored, 
+            user_reward.reward_per_token_paid, 
+            user_reward.rewards, 
+            user_reward.rewards_dust)
+    }
+
+    /// Returns whole reward tokens earned by the user and the scaled remainder below one token
+    fn calc_earned(
+        stake_amount: u64, 
+        rewards_per_token: u128, 
+        user_reward_per_token_paid: u128, 
+        user_rewards: u64, 
+        user_rewards_dust: u128) -> Result<(u64, u128), ProgramError> {
+        let scaled_earned = rewards_per_token
+            .checked_sub(user_reward_per_token_paid)
+            .and_then(|rewards_per_token_delta| rewards_per_token_delta.checked_mul(stake_amount as u128))
+            .and_then(|rewards| rewards.checked_add(user_rewards_dust))
+            .ok_or(StakingError::MathOverflow)?;
+        let new_rewards = u64::try_from(scaled_earned / Self::PRECISION).map_err(|_| StakingError::MathOverflow)?;
+        let rewards = user_rewards.checked_add(new_rewards).ok_or(StakingError::MathOverflow)?;
 
-        earned
+        Ok((rewards, scaled_earned % Self::PRECISION))
     }
-}
\ No newline at end of file
+}
@@ -1,47 +1,268 @@
 
 use solana_program::pubkey::Pubkey;
 use solana_program::clock::UnixTimestamp;
+use solana_program::program_error::ProgramError;
+use solana_program::entrypoint::ProgramResult;
 use borsh::{BorshSerialize, BorshDeserialize};
+use crate::error::StakingError;
 
-#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default)]
 pub struct StakingState {
+    pub is_initialized: bool,
     pub admin: Pubkey,
+    pub pending_admin: Option<Pubkey>,
+    /// Admin which created the pool. Seeds the metadata pda, so nobody else can take its address.
+    pub creator: Pubkey,
     pub staking_token_mint: Pubkey,
-    pub reward_token_mint: Pubkey,
+    pub staking_escrow: Pubkey,
     pub total_supply: u64, 
-    pub reward_per_token_stored: u64,
-    pub last_update_timestamp: UnixTimestamp,
+    /// Sum of boosted user balances. Rewards are distributed over it instead of `total_supply`.
+    pub effective_supply: u64,
+    /// User state accounts opened in this pool which are not closed yet
+    pub open_positions: u64,
+    /// Stake of all unbonding entries, still held by the staking escrow
+    pub total_unbonding: u64,
+    /// Slot of `Init`. Positions left over from a closed pool at the same address carry an older one.
+    pub generation: u64,
+    pub stake_paused: bool,
+    pub unstake_paused: bool,
+    pub claim_paused: bool,
+    /// Reward streams. The first one is created by `Init` and seeds the metadata pda.
+    pub rewards: Vec<RewardInfo>,
+    pub lock_tiers: Vec<LockTier>,
+    /// Cooldown in seconds between `RequestUnstake` and `WithdrawUnbonded`. Zero allows plain `Unstake`.
+    pub unbonding_period: u64,
+    /// Cap of `total_supply`. Zero means unlimited.
+    pub max_total_stake: u64,
+    /// Cap of a single user balance. Zero means unlimited.
+    pub max_stake_per_user: u64,
+    /// Smallest amount accepted by a single deposit
+    pub min_stake_amount: u64,
+    pub penalty: PenaltyConfig,
+    /// Mint of receipt tokens issued 1:1 for staked tokens. Receipts must be burned to leave the pool.
+    pub receipt_mint: Option<Pubkey>,
 }
 
 impl StakingState {
-    pub const LEN: usize = 32 * 3 + 8 * 3;
+    pub const MAX_REWARD_TOKENS: usize = 4;
+    pub const MAX_LOCK_TIERS: usize = 8;
+    pub const LEN: usize = 1 + 32 + (1 + 32) + 32 * 3 + 8 * 5 + 3 
+        + 4 + RewardInfo::LEN * Self::MAX_REWARD_TOKENS
+        + 4 + LockTier::LEN * Self::MAX_LOCK_TIERS
+        + 8 * 4
+        + PenaltyConfig::LEN
+        + (1 + 32);
 
-    pub fn unpack(data: &mut [u8]) -> Self {
-        StakingState::try_from_slice(data).unwrap()
+    pub fn unpack(data: &mut [u8]) -> Result<Self, ProgramError> {
+        StakingState::deserialize(&mut &data[..]).map_err(|_| StakingError::InvalidStateData.into())
     }
 
-    pub fn pack(&self, data: &mut [u8]) {
-        let encoded = self.try_to_vec().unwrap();
+    pub fn pack(&self, data: &mut [u8]) -> ProgramResult {
+        let encoded = self.try_to_vec().map_err(|_| StakingError::InvalidStateData)?;
+
+        if data.len() < encoded.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
         data[..encoded.len()].copy_from_slice(&encoded);
+        Ok(())
+    }
+
+    /// Rewards do not accrue while every pool operation is paused
+    pub fn is_fully_paused(&self) -> bool {
+        self.stake_paused && self.unstake_paused && self.claim_paused
+    }
+
+    pub fn reward(&self, reward_index: usize) -> Result<&RewardInfo, ProgramError> {
+        self.rewards.get(reward_index).ok_or_else(|| StakingError::InvalidRewardIndex.into())
+    }
+
+    pub fn reward_mut(&mut self, reward_index: usize) -> Result<&mut RewardInfo, ProgramError> {
+        self.rewards.get_mut(reward_index).ok_or_else(|| StakingError::InvalidRewardIndex.into())
+    }
+
+    pub fn lock_tier(&self, lock_duration: u64) -> Result<LockTier, ProgramError> {
+        self.lock_tiers
+            .iter()
+            .find(|lock_tier| lock_tier.duration == lock_duration)
+            .copied()
+            .ok_or_else(|| StakingError::InvalidLockDuration.into())
+    }
+
+    /// Index of the reward stream paying the staking token, which redistributes penalties
+    pub fn staking_reward_index(&self) -> Option<usize> {
+        self.rewards.iter().position(|reward| reward.mint == self.staking_token_mint)
+    }
+
+    /// Token account receiving early unstake penalties
+    pub fn penalty_destination(&self) -> Option<Pubkey> {
+        match self.penalty.destination {
+            PenaltyDestination::Treasury(treasury) => Some(treasury),
+            PenaltyDestination::Redistribute => self.staking_reward_index().map(|reward_index| self.rewards[reward_index].escrow),
+        }
     }
 }
 
-#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default, Clone)]
+pub struct RewardInfo {
+    pub mint: Pubkey,
+    pub escrow: Pubkey,
+    pub reward_rate: u64,
+    pub reward_per_token_stored: u128,
+    pub reward_dust: u128,
+    pub last_update_timestamp: UnixTimestamp,
+    pub period_finish: UnixTimestamp,
+    /// Rewards distributed to stakers and not claimed yet. Rounding dust keeps it
+    /// slightly above what users can actually claim.
+    pub owed_rewards: u64,
+    /// Rewards transferred into the escrow through the program
+    pub total_funded: u64,
+    /// Funding which is not part of a rewards period yet. The next `NotifyRewardAmount` schedules it.
+    pub unscheduled_funding: u64,
+}
+
+impl RewardInfo {
+    pub const LEN: usize = 32 * 2 + 8 * 6 + 16 * 2;
+}
+
+/// Early unstake penalty. Stake leaving a position within `period` seconds after the last stake
+/// pays `penalty_bps` of the amount, decaying linearly to zero if `linear_decay` is set.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default, Clone, Copy)]
+pub struct PenaltyConfig {
+    pub period: u64,
+    pub penalty_bps: u16,
+    pub linear_decay: bool,
+    pub destination: PenaltyDestination,
+}
+
+impl PenaltyConfig {
+    pub const LEN: usize = 8 + 2 + 1 + PenaltyDestination::LEN;
+    pub const MAX_PENALTY_BPS: u16 = 10_000;
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default, Clone, Copy)]
+pub enum PenaltyDestination {
+    /// Staking token account of the pool treasury
+    Treasury(Pubkey),
+    /// Distributed to remaining stakers through the reward stream paying the staking token
+    #[default]
+    Redistribute,
+}
+
+impl PenaltyDestination {
+    pub const LEN: usize = 1 + 32;
+}
+
+/// Lock duration in seconds and the reward multiplier it grants, in basis points
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default, Clone, Copy)]
+pub struct LockTier {
+    pub duration: u64,
+    pub multiplier_bps: u16,
+}
+
+impl LockTier {
+    pub const LEN: usize = 8 + 2;
+    /// Multiplier of a flexible (unlocked) stake
+    pub const BASE_MULTIPLIER_BPS: u16 = 10_000;
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default)]
 pub struct UserStakingState {
     pub balance: u64, 
-    pub reward_per_token_paid: u64,
-    pub rewards: u64,
+    /// `balance` boosted by `multiplier_bps`
+    pub effective_balance: u64,
+    pub multiplier_bps: u16,
+    /// Zero for a flexible stake
+    pub lock_end: UnixTimestamp,
+    pub last_stake_timestamp: UnixTimestamp,
+    /// Time of the last reward checkpoint. The boost of an expired lock is credited up to `lock_end` from it.
+    pub last_checkpoint_timestamp: UnixTimestamp,
+    /// Checkpoints per reward stream, in the same order as `StakingState::rewards`
+    pub rewards: Vec<UserRewardInfo>,
+    /// Requested unstakes waiting for the pool unbonding period. They do not earn rewards.
+    pub unbonding: Vec<UnbondingEntry>,
+    /// Wallet allowed to trigger `GetRewards` on behalf of the owner
+    pub claim_delegate: Option<Pubkey>,
+    /// Owner of the token accounts rewards must be paid to. Delegated claims default to the position owner.
+    pub reward_destination: Option<Pubkey>,
+    /// `StakingState::generation` of the pool the position was opened in
+    pub generation: u64,
 }
 
 impl UserStakingState {
+    pub const MAX_UNBONDING_ENTRIES: usize = 8;
+    pub const LEN: usize = 8 * 2 + 2 + 8 * 3 
+        + 4 + UserRewardInfo::LEN * StakingState::MAX_REWARD_TOKENS
+        + 4 + UnbondingEntry::LEN * Self::MAX_UNBONDING_ENTRIES
+        + 33 * 2
+        + 8;
+
+    pub fn unpack(data: &mut [u8]) -> Result<Self, ProgramError> {
+        UserStakingState::deserialize(&mut &data[..]).map_err(|_| StakingError::InvalidStateData.into())
+    }
+
+    pub fn pack(&self, data: &mut [u8]) -> ProgramResult {
+        let encoded = self.try_to_vec().map_err(|_| StakingError::InvalidStateData)?;
+
+        if data.len() < encoded.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        data[..encoded.len()].copy_from_slice(&encoded);
+        Ok(())
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default, Clone)]
+pub struct UserRewardInfo {
+    pub reward_per_token_paid: u128,
+    pub rewards: u64,
+    pub rewards_dust: u128,
+}
+
+impl UserRewardInfo {
+    pub const LEN: usize = 16 * 2 + 8;
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default, Clone)]
+pub struct UnbondingEntry {
+    pub amount: u64,
+    pub maturity: UnixTimestamp,
+    /// Early unstake penalty charged on request, withheld from `amount` on withdrawal
+    pub penalty: u64,
+}
+
+impl UnbondingEntry {
     pub const LEN: usize = 8 * 3;
+}
+
+/// Program wide settings shared by every pool
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Default)]
+pub struct GlobalConfig {
+    pub is_initialized: bool,
+    pub authority: Pubkey,
+    /// Protocol fee skimmed from every rewards payout
+    pub fee_bps: u16,
+    /// Owner of the token accounts receiving protocol fees
+    pub fee_recipient: Pubkey,
+}
+
+impl GlobalConfig {
+    pub const LEN: usize = 1 + 32 + 2 + 32;
+    pub const MAX_FEE_BPS: u16 = 10_000;
 
-    pub fn unpack(data: &mut [u8]) -> Self {
-        UserStakingState::try_from_slice(data).unwrap()
+    pub fn unpack(data: &mut [u8]) -> Result<Self, ProgramError> {
+        GlobalConfig::deserialize(&mut &data[..]).map_err(|_| StakingError::InvalidStateData.into())
     }
 
-    pub fn pack(&self, data: &mut [u8]) {
-        let encoded = self.try_to_vec().unwrap();
+    pub fn pack(&self, data: &mut [u8]) -> ProgramResult {
+        let encoded = self.try_to_vec().map_err(|_| StakingError::InvalidStateData)?;
+
+        if data.len() < encoded.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
         data[..encoded.len()].copy_from_slice(&encoded);
+        Ok(())
     }
-}
\ No newline at end of file
+}
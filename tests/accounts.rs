@@ -0,0 +1,223 @@
+use program2::accounts::{StakeAccounts, TransferPositionAccounts};
+use program2::error::StakingError;
+use program2::pda_helper::PdaHelper;
+use program2::state::{RewardInfo, StakingState, UserStakingState};
+use solana_program::account_info::AccountInfo;
+use solana_program::program_pack::Pack;
+use solana_program::pubkey::Pubkey;
+use solana_program::system_program;
+use spl_token::state::{Account, AccountState};
+
+/// Backing storage of an `AccountInfo` passed to the account validation
+struct TestAccount {
+    key: Pubkey,
+    owner: Pubkey,
+    is_signer: bool,
+    lamports: u64,
+    data: Vec<u8>,
+}
+
+impl TestAccount {
+    fn new(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+        TestAccount { key, owner, is_signer: false, lamports: 1, data }
+    }
+
+    fn signer(key: Pubkey) -> Self {
+        TestAccount { is_signer: true, ..Self::new(key, system_program::id(), vec![]) }
+    }
+
+    fn token_account(mint: Pubkey, owner: Pubkey) -> Self {
+        let mut data = vec![0; Account::LEN];
+        Account {
+            mint,
+            owner,
+            state: AccountState::Initialized,
+            ..Account::default()
+        }.pack_into_slice(&mut data);
+
+        Self::new(Pubkey::new_unique(), spl_token::id(), data)
+    }
+}
+
+fn to_account_infos(accounts: &mut [TestAccount]) -> Vec<AccountInfo<'_>> {
+    accounts
+        .iter_mut()
+        .map(|account| AccountInfo::new(
+            &account.key,
+            account.is_signer,
+            true,
+            &mut account.lamports,
+            &mut account.data,
+            &account.owner,
+            false,
+            0))
+        .collect()
+}
+
+/// Initialized pool issuing receipts of its own mint
+struct ReceiptPool {
+    program_id: Pubkey,
+    state: StakingState,
+    metadata: Pubkey,
+    receipt_mint: Pubkey,
+}
+
+impl ReceiptPool {
+    fn new() -> Self {
+        let program_id = Pubkey::new_unique();
+        let state = StakingState {
+            is_initialized: true,
+            creator: Pubkey::new_unique(),
+            staking_token_mint: Pubkey::new_unique(),
+            staking_escrow: Pubkey::new_unique(),
+            rewards: vec![RewardInfo {
+                mint: Pubkey::new_unique(),
+                ..RewardInfo::default()
+            }],
+            receipt_mint: Some(Pubkey::new_unique()),
+            ..StakingState::default()
+        };
+        let (metadata, _) = PdaHelper::find_metadata_pda(&state.staking_token_mint, &state.rewards[0].mint, &state.creator, &program_id);
+        let receipt_mint = state.receipt_mint.unwrap();
+
+        ReceiptPool { program_id, state, metadata, receipt_mint }
+    }
+
+    fn metadata_account(&self) -> TestAccount {
+        let mut data = vec![0; StakingState::LEN];
+        self.state.pack(&mut data).unwrap();
+
+        TestAccount::new(self.metadata, self.program_id, data)
+    }
+
+    fn user_state_account(&self, user: &Pubkey, initialized: bool) -> TestAccount {
+        let (user_state, _) = PdaHelper::find_user_state_pda(&self.metadata, user, &self.program_id);
+
+        if initialized {
+            TestAccount::new(user_state, self.program_id, vec![0; UserStakingState::LEN])
+        } else {
+            TestAccount::new(user_state, system_program::id(), vec![])
+        }
+    }
+
+    /// Receipt accounts 8 to 11 of `Stake` with the receipt token account owned by `owner`
+    fn receipt_accounts(&self, owner: Pubkey) -> Vec<TestAccount> {
+        let (receipt_authority, _) = PdaHelper::find_receipt_authority_pda(&self.metadata, &self.program_id);
+
+        vec![
+            TestAccount::new(self.receipt_mint, spl_token::id(), vec![]),
+            TestAccount::token_account(self.receipt_mint, owner),
+            TestAccount::new(receipt_authority, self.program_id, vec![]),
+            TestAccount::new(spl_token::id(), Pubkey::default(), vec![]),
+        ]
+    }
+
+    fn stake_for_accounts(&self, funder: Pubkey, beneficiary: Pubkey, receipt_owner: Pubkey) -> Vec<TestAccount> {
+        let mut accounts = vec![
+            TestAccount::signer(funder),
+            TestAccount::token_account(self.state.staking_token_mint, funder),
+            TestAccount::new(self.state.staking_escrow, spl_token::id(), vec![]),
+            self.user_state_account(&beneficiary, false),
+            self.metadata_account(),
+            TestAccount::new(self.state.staking_token_mint, spl_token::id(), vec![]),
+            TestAccount::new(spl_token::id(), Pubkey::default(), vec![]),
+            TestAccount::new(system_program::id(), Pubkey::default(), vec![]),
+        ];
+        accounts.extend(self.receipt_accounts(receipt_owner));
+        accounts
+    }
+
+    fn transfer_accounts(&self, user: Pubkey, to: Pubkey, recipient_receipt_owner: Pubkey) -> Vec<TestAccount> {
+        let mut accounts = vec![
+            TestAccount::signer(user),
+            self.user_state_account(&user, true),
+            self.user_state_account(&to, false),
+            self.metadata_account(),
+            TestAccount::new(system_program::id(), Pubkey::default(), vec![]),
+        ];
+        accounts.extend(self.receipt_accounts(user));
+        accounts.push(TestAccount::token_account(self.receipt_mint, recipient_receipt_owner));
+        accounts
+    }
+}
+
+#[test]
+fn stake_for_mints_receipts_to_the_beneficiary() {
+    let pool = ReceiptPool::new();
+    let funder = Pubkey::new_unique();
+    let beneficiary = Pubkey::new_unique();
+
+    let mut accounts = pool.stake_for_accounts(funder, beneficiary, beneficiary);
+    let account_infos = to_account_infos(&mut accounts);
+    let stake_accounts = StakeAccounts::try_from_accounts(&pool.program_id, &account_infos, Some(beneficiary)).unwrap();
+    assert_eq!(stake_accounts.receipt.unwrap().user_receipt_token_ai.key, account_infos[9].key);
+
+    let mut accounts = pool.stake_for_accounts(funder, beneficiary, funder);
+    let account_infos = to_account_infos(&mut accounts);
+    assert_eq!(
+        StakeAccounts::try_from_accounts(&pool.program_id, &account_infos, Some(beneficiary)).err(),
+        Some(StakingError::InvalidAccountOwner.into()));
+}
+
+#[test]
+fn receipts_must_be_of_the_pool_receipt_mint() {
+    let pool = ReceiptPool::new();
+    let user = Pubkey::new_unique();
+
+    let mut accounts = pool.stake_for_accounts(user, user, user);
+    accounts[9] = TestAccount::token_account(Pubkey::new_unique(), user);
+    let account_infos = to_account_infos(&mut accounts);
+
+    assert_eq!(
+        StakeAccounts::try_from_accounts(&pool.program_id, &account_infos, None).err(),
+        Some(StakingError::InvalidReceiptMint.into()));
+}
+
+#[test]
+fn transfer_position_moves_receipts_to_the_recipient() {
+    let pool = ReceiptPool::new();
+    let user = Pubkey::new_unique();
+    let to = Pubkey::new_unique();
+
+    let mut accounts = pool.transfer_accounts(user, to, to);
+    let account_infos = to_account_infos(&mut accounts);
+    let transfer_accounts = TransferPositionAccounts::try_from_accounts(&pool.program_id, &account_infos, &to).unwrap();
+    let receipt = transfer_accounts.receipt.unwrap();
+    let recipient_receipt = transfer_accounts.recipient_receipt.unwrap();
+    assert_eq!(receipt.user_receipt_token_ai.key, account_infos[6].key);
+    assert_eq!(recipient_receipt.user_receipt_token_ai.key, account_infos[9].key);
+    assert_eq!(recipient_receipt.receipt_mint_ai.key, receipt.receipt_mint_ai.key);
+
+    let mut accounts = pool.transfer_accounts(user, to, user);
+    let account_infos = to_account_infos(&mut accounts);
+    assert_eq!(
+        TransferPositionAccounts::try_from_accounts(&pool.program_id, &account_infos, &to).err(),
+        Some(StakingError::InvalidAccountOwner.into()));
+}
+
+#[test]
+fn positions_of_a_closed_pool_are_stale() {
+    let mut pool = ReceiptPool::new();
+    pool.state.generation = 2;
+    let user = Pubkey::new_unique();
+    let to = Pubkey::new_unique();
+
+    let mut accounts = pool.transfer_accounts(user, to, to);
+    let account_infos = to_account_infos(&mut accounts);
+    assert_eq!(
+        TransferPositionAccounts::try_from_accounts(&pool.program_id, &account_infos, &to).err(),
+        Some(StakingError::StalePosition.into()));
+}
+
+#[test]
+fn metadata_address_belongs_to_the_pool_creator() {
+    let mut pool = ReceiptPool::new();
+    let user = Pubkey::new_unique();
+    pool.state.creator = Pubkey::new_unique();
+
+    let mut accounts = pool.stake_for_accounts(user, user, user);
+    let account_infos = to_account_infos(&mut accounts);
+    assert_eq!(
+        StakeAccounts::try_from_accounts(&pool.program_id, &account_infos, None).err(),
+        Some(StakingError::InvalidPda.into()));
+}
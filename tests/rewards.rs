@@ -0,0 +1,745 @@
+use program2::error::StakingError;
+use program2::processor::Processor;
+use program2::state::{GlobalConfig, LockTier, PenaltyConfig, PenaltyDestination, RewardInfo, StakingState, UnbondingEntry, UserStakingState};
+use solana_program::clock::UnixTimestamp;
+use solana_program::entrypoint::ProgramResult;
+use solana_program::pubkey::Pubkey;
+
+const TOKEN: u64 = 1_000_000_000;
+
+/// Drives the processor reward math the same way `stake`, `unstake` and `get_rewards` do,
+/// and keeps an exact per-second reference model of the first reward stream next to it.
+/// The model boosts locked stake by its own record of lock ends, not by the processor state.
+struct Pool {
+    state: StakingState,
+    users: Vec<UserStakingState>,
+    locks: Vec<(UnixTimestamp, u16)>,
+    paid: Vec<u64>,
+    expected: Vec<f64>,
+    now: UnixTimestamp,
+}
+
+impl Pool {
+    fn new(users: usize, reward_rate: u64, duration: UnixTimestamp) -> Self {
+        let state = StakingState {
+            admin: Pubkey::new_unique(),
+            staking_token_mint: Pubkey::new_unique(),
+            rewards: vec![RewardInfo {
+                mint: Pubkey::new_unique(),
+                reward_rate,
+                period_finish: duration,
+                ..RewardInfo::default()
+            }],
+            ..StakingState::default()
+        };
+
+        Pool {
+            state,
+            users: (0..users).map(|_| UserStakingState::default()).collect(),
+            locks: vec![(0, LockTier::BASE_MULTIPLIER_BPS); users],
+            paid: vec![0; users],
+            expected: vec![0.0; users],
+            now: 0,
+        }
+    }
+
+    fn advance(&mut self, seconds: UnixTimestamp) {
+        for _ in 0..seconds {
+            let weights: Vec<f64> = self.users.iter().zip(self.locks.iter()).map(|(user, (lock_end, multiplier_bps))| {
+                let multiplier_bps = if self.now < *lock_end { *multiplier_bps } else { LockTier::BASE_MULTIPLIER_BPS };
+                user.balance as f64 * multiplier_bps as f64 / LockTier::BASE_MULTIPLIER_BPS as f64
+            }).collect();
+            let supply: f64 = weights.iter().sum();
+
+            let reward = &self.state.rewards[0];
+            if self.now < reward.period_finish && supply > 0.0 && !self.state.is_fully_paused() {
+                for (weight, expected) in weights.iter().zip(self.expected.iter_mut()) {
+                    *expected += reward.reward_rate as f64 * weight / supply;
+                }
+            }
+            self.now += 1;
+        }
+    }
+
+    fn settle(&mut self, user: usize) {
+        Processor::accrue_rewards(&mut self.state, self.now).unwrap();
+        Processor::checkpoint_user(&mut self.state, &mut self.users[user], self.now).unwrap();
+    }
+
+    fn set_fully_paused(&mut self, paused: bool) {
+        Processor::accrue_rewards(&mut self.state, self.now).unwrap();
+        self.state.stake_paused = paused;
+        self.state.unstake_paused = paused;
+        self.state.claim_paused = paused;
+    }
+
+    fn stake(&mut self, user: usize, amount: u64) {
+        self.settle(user);
+        let multiplier_bps = if self.users[user].lock_end > self.now {
+            self.users[user].multiplier_bps
+        } else {
+            LockTier::BASE_MULTIPLIER_BPS
+        };
+        self.set_stake(user, self.users[user].balance + amount, multiplier_bps);
+    }
+
+    fn stake_locked(&mut self, user: usize, amount: u64, lock_tier: LockTier) {
+        self.settle(user);
+        self.users[user].lock_end = self.now + lock_tier.duration as UnixTimestamp;
+        self.locks[user] = (self.users[user].lock_end, lock_tier.multiplier_bps);
+        self.set_stake(user, self.users[user].balance + amount, lock_tier.multiplier_bps);
+    }
+
+    fn unstake(&mut self, user: usize, amount: u64) {
+        self.settle(user);
+        self.set_stake(user, self.users[user].balance - amount, LockTier::BASE_MULTIPLIER_BPS);
+    }
+
+    fn set_stake(&mut self, user: usize, balance: u64, multiplier_bps: u16) {
+        Processor::set_stake(&mut self.state, &mut self.users[user], balance, multiplier_bps).unwrap();
+    }
+
+    fn transfer(&mut self, from: usize, to: usize, amount: u64, recipient_signed: bool) -> ProgramResult {
+        self.settle(from);
+        self.settle(to);
+        let (low, high) = self.users.split_at_mut(from.max(to));
+        let (sender, recipient) = if from < to {
+            (&mut low[from], &mut high[0])
+        } else {
+            (&mut high[0], &mut low[to])
+        };
+        Processor::move_stake(&mut self.state, sender, recipient, amount, recipient_signed)
+    }
+
+    fn claim(&mut self, user: usize) -> u64 {
+        let rewards = self.claim_stream(user, 0);
+        self.paid[user] += rewards;
+        rewards
+    }
+
+    fn claim_stream(&mut self, user: usize, reward_index: usize) -> u64 {
+        self.settle(user);
+        Processor::take_rewards(&mut self.state, &mut self.users[user], reward_index).unwrap()
+    }
+
+    fn claim_all(&mut self) {
+        for user in 0..self.users.len() {
+            self.claim(user);
+        }
+    }
+
+    fn assert_matches_reference(&self) {
+        for (user, (paid, expected)) in self.paid.iter().zip(self.expected.iter()).enumerate() {
+            // rounding dust may hold back a couple of base units, or a billionth for whale stakes
+            let diff = (*paid as f64 - expected).abs();
+            assert!(diff <= 2.0 + expected * 1e-9, "user {} paid {} but reference model expects {}", user, paid, expected);
+        }
+
+        let emitted = self.state.rewards[0].reward_rate * (self.now as u64);
+        assert!(self.paid.iter().sum::<u64>() <= emitted, "paid more than emitted");
+    }
+}
+
+#[test]
+fn single_staker_receives_whole_emission() {
+    let mut pool = Pool::new(1, 100 * TOKEN, 1_000);
+
+    pool.stake(0, 10 * TOKEN);
+    pool.advance(100);
+
+    assert_eq!(pool.claim(0), 10_000 * TOKEN);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn repeated_claims_do_not_re_earn_history() {
+    let mut pool = Pool::new(1, 7 * TOKEN, 1_000);
+
+    pool.stake(0, 3 * TOKEN);
+    pool.advance(10);
+
+    assert!(pool.claim(0) >= 70 * TOKEN - 1);
+    assert_eq!(pool.claim(0), 0);
+
+    pool.stake(0, 3 * TOKEN);
+    assert_eq!(pool.claim(0), 0);
+
+    pool.advance(10);
+    assert!(pool.claim(0) >= 70 * TOKEN - 1);
+    assert_eq!(pool.claim(0), 0);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn late_staker_does_not_earn_earlier_rewards() {
+    let mut pool = Pool::new(2, 10 * TOKEN, 1_000);
+
+    pool.stake(0, 5 * TOKEN);
+    pool.advance(100);
+    pool.stake(1, 5 * TOKEN);
+    pool.advance(100);
+    pool.claim_all();
+
+    assert_eq!(pool.paid[0], 1_500 * TOKEN);
+    assert_eq!(pool.paid[1], 500 * TOKEN);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn accrual_stops_at_period_finish() {
+    let mut pool = Pool::new(1, TOKEN, 50);
+
+    pool.stake(0, TOKEN);
+    pool.advance(200);
+
+    assert_eq!(pool.claim(0), 50 * TOKEN);
+    pool.advance(200);
+    assert_eq!(pool.claim(0), 0);
+}
+
+#[test]
+fn small_stake_in_large_pool_still_earns() {
+    let mut pool = Pool::new(2, TOKEN, 100_000);
+
+    pool.stake(0, 1_000_000 * TOKEN);
+    pool.stake(1, TOKEN);
+    for _ in 0..100 {
+        pool.advance(60);
+        pool.claim(1);
+    }
+    pool.claim_all();
+
+    assert!(pool.paid[1] > 0);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn multi_user_stake_unstake_claim_sequence() {
+    let mut pool = Pool::new(3, 1_234_567_891, 5_000);
+
+    pool.stake(0, 100 * TOKEN);
+    pool.advance(37);
+    pool.stake(1, 250 * TOKEN + 17);
+    pool.advance(113);
+    pool.claim(0);
+    pool.stake(2, 3 * TOKEN);
+    pool.advance(401);
+    pool.unstake(1, 50 * TOKEN);
+    pool.advance(29);
+    pool.claim(2);
+    pool.unstake(0, 100 * TOKEN);
+    pool.advance(777);
+    pool.stake(0, 42 * TOKEN + 3);
+    pool.claim(1);
+    pool.advance(1_000);
+    pool.unstake(2, 3 * TOKEN);
+    pool.advance(3_000);
+    pool.claim_all();
+
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn rewards_without_stakers_are_not_distributed() {
+    let mut pool = Pool::new(2, TOKEN, 1_000);
+
+    pool.advance(100);
+    pool.stake(0, TOKEN);
+    pool.advance(100);
+    pool.unstake(0, TOKEN);
+    pool.advance(100);
+    pool.stake(1, TOKEN);
+    pool.advance(100);
+    pool.claim_all();
+
+    assert_eq!(pool.paid[0], 100 * TOKEN);
+    assert_eq!(pool.paid[1], 100 * TOKEN);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn overflowing_accrual_returns_math_overflow() {
+    let mut pool = Pool::new(1, u64::MAX, UnixTimestamp::MAX);
+
+    pool.stake(0, 1);
+    pool.now = UnixTimestamp::MAX / 2;
+
+    assert_eq!(
+        Processor::accrue_rewards(&mut pool.state, pool.now),
+        Err(StakingError::MathOverflow.into())
+    );
+}
+
+#[test]
+fn fully_paused_pool_freezes_accrual_and_extends_period() {
+    let mut pool = Pool::new(1, TOKEN, 100);
+
+    pool.stake(0, TOKEN);
+    pool.advance(40);
+    pool.set_fully_paused(true);
+    pool.advance(500);
+    pool.set_fully_paused(false);
+
+    assert_eq!(pool.claim(0), 40 * TOKEN);
+    assert_eq!(pool.state.rewards[0].period_finish, pool.now + 60);
+
+    pool.advance(1_000);
+    assert_eq!(pool.claim(0), 60 * TOKEN);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn reward_streams_accrue_independently() {
+    let mut pool = Pool::new(2, 10 * TOKEN, 1_000);
+
+    pool.stake(0, 3 * TOKEN);
+    pool.advance(100);
+
+    // second stream starts later, with its own rate and period
+    Processor::accrue_rewards(&mut pool.state, pool.now).unwrap();
+    pool.state.rewards.push(RewardInfo {
+        mint: Pubkey::new_unique(),
+        reward_rate: 4 * TOKEN,
+        last_update_timestamp: pool.now,
+        period_finish: pool.now + 50,
+        ..RewardInfo::default()
+    });
+
+    pool.stake(1, TOKEN);
+    pool.advance(200);
+
+    assert_eq!(pool.claim_stream(0, 1), 150 * TOKEN);
+    assert_eq!(pool.claim_stream(1, 1), 50 * TOKEN);
+    assert_eq!(pool.claim_stream(0, 1), 0);
+
+    pool.claim_all();
+    assert!(pool.paid[0] >= 2_500 * TOKEN - 1);
+    assert_eq!(pool.paid[1], 500 * TOKEN);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn locked_stake_earns_boosted_share() {
+    let mut pool = Pool::new(2, 30 * TOKEN, 1_000);
+    let one_year = LockTier { duration: 365 * 24 * 60 * 60, multiplier_bps: 20_000 };
+
+    pool.stake(0, 10 * TOKEN);
+    pool.stake_locked(1, 10 * TOKEN, one_year);
+    pool.advance(100);
+    pool.claim_all();
+
+    assert_eq!(pool.state.total_supply, 20 * TOKEN);
+    assert_eq!(pool.state.effective_supply, 30 * TOKEN);
+    assert_eq!(pool.paid[0], 1_000 * TOKEN);
+    assert_eq!(pool.paid[1], 2_000 * TOKEN);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn boost_is_credited_only_until_lock_end() {
+    let mut pool = Pool::new(2, 30 * TOKEN, 1_000);
+    let lock_tier = LockTier { duration: 100, multiplier_bps: 20_000 };
+
+    pool.stake(0, 10 * TOKEN);
+    pool.stake_locked(1, 10 * TOKEN, lock_tier);
+
+    // topping up a locked position keeps its lock and boost
+    pool.advance(50);
+    pool.stake(1, 5 * TOKEN);
+    assert_eq!(pool.users[1].effective_balance, 30 * TOKEN);
+    assert_eq!(pool.users[1].lock_end, 100);
+
+    // 1_000 before the top up and 22.5 per second until the lock end. After it only the base
+    // share of 11.25 per second is credited, the boosted share is redistributed to both stakers.
+    pool.advance(100);
+    assert_eq!(pool.claim(1), 2_687_500_000_000);
+    assert_eq!(pool.users[1].lock_end, 0);
+    assert_eq!(pool.users[1].effective_balance, 15 * TOKEN);
+    assert_eq!(pool.state.effective_supply, 25 * TOKEN);
+
+    pool.advance(100);
+    pool.claim_all();
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn owed_rewards_back_every_pending_claim() {
+    let mut pool = Pool::new(3, 1_234_567, 1_000);
+
+    pool.stake(0, 7 * TOKEN);
+    pool.stake(1, 3 * TOKEN + 1);
+    pool.advance(300);
+    pool.claim(0);
+    pool.stake(2, 11 * TOKEN);
+    pool.advance(300);
+    for user in 0..3 {
+        pool.settle(user);
+    }
+
+    let pending: u64 = pool.users.iter().map(|user| user.rewards[0].rewards).sum();
+    let owed = pool.state.rewards[0].owed_rewards;
+    assert!(owed >= pending, "owed {} does not cover pending {}", owed, pending);
+    assert!(owed - pending <= 3, "owed {} drifted from pending {}", owed, pending);
+
+    pool.advance(1_000);
+    pool.claim_all();
+    assert!(pool.state.rewards[0].owed_rewards <= 3);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn pool_closes_once_stake_unbonding_and_owed_rewards_are_gone() {
+    let mut pool = Pool::new(2, TOKEN, 1_000);
+    pool.state.open_positions = 2;
+
+    pool.stake(0, TOKEN);
+    pool.stake(1, 2 * TOKEN);
+    pool.advance(100);
+    assert_eq!(Processor::check_pool_closable(&pool.state), Err(StakingError::PoolNotEmpty.into()));
+
+    pool.unstake(0, TOKEN);
+    pool.unstake(1, 2 * TOKEN);
+    assert_eq!(Processor::check_pool_closable(&pool.state), Err(StakingError::PoolNotEmpty.into()));
+
+    pool.claim_all();
+    assert_eq!(Processor::check_pool_closable(&pool.state), Ok(()));
+
+    pool.state.total_unbonding = TOKEN;
+    assert_eq!(Processor::check_pool_closable(&pool.state), Err(StakingError::PoolNotEmpty.into()));
+}
+
+#[test]
+fn excess_rewards_exclude_owed_and_committed_rewards() {
+    let mut pool = Pool::new(1, TOKEN, 1_000);
+
+    pool.stake(0, TOKEN);
+    pool.advance(400);
+    pool.settle(0);
+
+    // 400 owed to the staker, 600 still to be emitted
+    let reward = &pool.state.rewards[0];
+    assert_eq!(Processor::excess_rewards(reward, 1_500 * TOKEN, pool.now).unwrap(), 500 * TOKEN);
+    assert_eq!(Processor::excess_rewards(reward, 900 * TOKEN, pool.now).unwrap(), 0);
+
+    pool.advance(1_000);
+    pool.claim(0);
+    let reward = &pool.state.rewards[0];
+    assert_eq!(Processor::excess_rewards(reward, 500 * TOKEN, pool.now).unwrap(), 500 * TOKEN);
+}
+
+#[test]
+fn reward_rate_must_be_funded_on_top_of_owed_and_unscheduled_rewards() {
+    let mut pool = Pool::new(1, TOKEN, 1_000);
+
+    pool.stake(0, TOKEN);
+    pool.advance(400);
+    pool.settle(0);
+    pool.state.rewards[0].unscheduled_funding = 100 * TOKEN;
+
+    // 400 owed, 100 unscheduled and 600 seconds left at the new rate
+    pool.state.rewards[0].reward_rate = 2 * TOKEN;
+    assert_eq!(Processor::check_rewards_funding(&pool.state.rewards[0], 1_700 * TOKEN, pool.now), Ok(()));
+    assert_eq!(
+        Processor::check_rewards_funding(&pool.state.rewards[0], 1_700 * TOKEN - 1, pool.now),
+        Err(StakingError::InsufficientRewardsFunding.into()));
+
+    // the rate alone fits into the escrow, owed and unscheduled rewards do not
+    assert_eq!(
+        Processor::check_rewards_funding(&pool.state.rewards[0], 1_200 * TOKEN, pool.now),
+        Err(StakingError::InsufficientRewardsFunding.into()));
+}
+
+#[test]
+fn funding_extends_an_active_period_and_keeps_the_rest_unscheduled() {
+    let mut pool = Pool::new(1, 10 * TOKEN, 100);
+
+    pool.stake(0, TOKEN);
+    pool.advance(50);
+    pool.settle(0);
+    Processor::extend_period(&mut pool.state.rewards[0], 205 * TOKEN, pool.now).unwrap();
+    assert_eq!(pool.state.rewards[0].period_finish, 120);
+    assert_eq!(pool.state.rewards[0].unscheduled_funding, 5 * TOKEN);
+
+    pool.advance(100);
+    assert_eq!(pool.claim(0), 1_200 * TOKEN);
+
+    Processor::extend_period(&mut pool.state.rewards[0], 500 * TOKEN, pool.now).unwrap();
+    assert_eq!(pool.state.rewards[0].period_finish, 120);
+    assert_eq!(pool.state.rewards[0].unscheduled_funding, 505 * TOKEN);
+    assert_eq!(Processor::excess_rewards(&pool.state.rewards[0], 505 * TOKEN, pool.now).unwrap(), 0);
+    pool.assert_matches_reference();
+}
+
+#[test]
+fn early_unstake_penalty_is_flat_or_decays_linearly() {
+    let mut penalty = PenaltyConfig {
+        period: 1_000,
+        penalty_bps: 1_000,
+        linear_decay: false,
+        destination: PenaltyDestination::Treasury(Pubkey::new_unique()),
+    };
+
+    assert_eq!(Processor::calc_penalty(&penalty, 100 * TOKEN, 500, 500).unwrap(), 10 * TOKEN);
+    assert_eq!(Processor::calc_penalty(&penalty, 100 * TOKEN, 500, 1_499).unwrap(), 10 * TOKEN);
+    assert_eq!(Processor::calc_penalty(&penalty, 100 * TOKEN, 500, 1_500).unwrap(), 0);
+
+    penalty.linear_decay = true;
+    assert_eq!(Processor::calc_penalty(&penalty, 100 * TOKEN, 500, 500).unwrap(), 10 * TOKEN);
+    assert_eq!(Processor::calc_penalty(&penalty, 100 * TOKEN, 500, 750).unwrap(), 7_500_000_000);
+    assert_eq!(Processor::calc_penalty(&penalty, 100 * TOKEN, 500, 1_500).unwrap(), 0);
+}
+
+#[test]
+fn stake_for_keeps_donated_stake_inside_the_penalty_window() {
+    let penalty = PenaltyConfig {
+        period: 1_000,
+        penalty_bps: 1_000,
+        linear_decay: false,
+        destination: PenaltyDestination::Treasury(Pubkey::new_unique()),
+    };
+    let mut pool = Pool::new(2, TOKEN, 10_000);
+
+    // a new position funded by another wallet starts its window at the deposit
+    pool.advance(5_000);
+    let deposit_timestamp = Processor::deposit_timestamp(&pool.users[0], 10 * TOKEN, pool.now).unwrap();
+    pool.users[0].last_stake_timestamp = deposit_timestamp;
+    pool.stake(0, 10 * TOKEN);
+    assert_eq!(deposit_timestamp, 5_000);
+    assert_eq!(Processor::calc_penalty(&penalty, 10 * TOKEN, pool.users[0].last_stake_timestamp, pool.now).unwrap(), TOKEN);
+
+    // topping up a settled position only pulls its window forward by the donated share
+    pool.advance(2_000);
+    let deposit_timestamp = Processor::deposit_timestamp(&pool.users[0], 30 * TOKEN, pool.now).unwrap();
+    pool.users[0].last_stake_timestamp = deposit_timestamp;
+    pool.stake(0, 30 * TOKEN);
+    assert_eq!(deposit_timestamp, 6_500);
+    assert_eq!(Processor::calc_penalty(&penalty, 40 * TOKEN, pool.users[0].last_stake_timestamp, pool.now).unwrap(), 4 * TOKEN);
+
+    pool.advance(1_000);
+    assert_eq!(Processor::calc_penalty(&penalty, 40 * TOKEN, pool.users[0].last_stake_timestamp, pool.now).unwrap(), 0);
+}
+
+#[test]
+fn redistributed_penalty_goes_to_remaining_stakers() {
+    let mut pool = Pool::new(2, TOKEN, 1_000);
+
+    pool.stake(0, TOKEN);
+    pool.stake(1, 3 * TOKEN);
+    pool.advance(100);
+    pool.settle(0);
+    Processor::redistribute(&mut pool.state, 0, 40 * TOKEN).unwrap();
+
+    assert_eq!(pool.claim(0), 35 * TOKEN);
+    assert_eq!(pool.claim(1), 105 * TOKEN);
+    assert!(pool.state.rewards[0].owed_rewards <= 1);
+}
+
+#[test]
+fn protocol_fee_is_skimmed_from_payout() {
+    let mut global_config = GlobalConfig::default();
+    assert_eq!(Processor::calc_fee(&global_config, 1_000 * TOKEN).unwrap(), 0);
+
+    global_config.fee_bps = 250;
+    assert_eq!(Processor::calc_fee(&global_config, 1_000 * TOKEN).unwrap(), 25 * TOKEN);
+    assert_eq!(Processor::calc_fee(&global_config, 39).unwrap(), 0);
+    assert_eq!(Processor::calc_fee(&global_config, u64::MAX).unwrap(), u64::MAX / 40);
+}
+
+#[test]
+fn transferred_stake_takes_pro_rated_rewards() {
+    let mut pool = Pool::new(2, 10 * TOKEN, 1_000);
+
+    pool.stake(0, 10 * TOKEN);
+    pool.advance(100);
+    pool.transfer(0, 1, 4 * TOKEN, false).unwrap();
+
+    assert_eq!(pool.users[0].balance, 6 * TOKEN);
+    assert_eq!(pool.users[1].balance, 4 * TOKEN);
+    assert_eq!(pool.state.total_supply, 10 * TOKEN);
+
+    pool.advance(100);
+    assert_eq!(pool.claim(0), 1_200 * TOKEN);
+    assert_eq!(pool.claim(1), 800 * TOKEN);
+}
+
+#[test]
+fn transferred_lock_keeps_boost_and_lock_end() {
+    let mut pool = Pool::new(3, TOKEN, 1_000);
+    let lock_tier = LockTier { duration: 100, multiplier_bps: 20_000 };
+
+    pool.stake_locked(0, 10 * TOKEN, lock_tier);
+    pool.stake(2, TOKEN);
+    pool.advance(10);
+    pool.transfer(0, 1, 10 * TOKEN, false).unwrap();
+
+    assert_eq!(pool.users[1].effective_balance, 20 * TOKEN);
+    assert_eq!(pool.users[1].lock_end, 100);
+    assert_eq!(pool.state.effective_supply, 21 * TOKEN);
+    assert_eq!(pool.transfer(1, 2, TOKEN, true), Err(StakingError::LockMismatch.into()));
+    assert_eq!(pool.transfer(2, 1, TOKEN, true), Err(StakingError::LockMismatch.into()));
+
+    pool.advance(100);
+    pool.settle(1);
+    assert_eq!(pool.users[1].effective_balance, 10 * TOKEN);
+    pool.transfer(2, 1, TOKEN, false).unwrap();
+    assert_eq!(pool.state.effective_supply, 11 * TOKEN);
+}
+
+#[test]
+fn extending_recipient_lock_needs_its_signature() {
+    let mut pool = Pool::new(2, TOKEN, 1_000);
+    let lock_tier = LockTier { duration: 100, multiplier_bps: 20_000 };
+
+    pool.stake_locked(0, 10 * TOKEN, lock_tier);
+    pool.advance(10);
+    pool.stake_locked(1, 10 * TOKEN, lock_tier);
+
+    // the earlier lock fits into the recipient lock
+    pool.transfer(0, 1, TOKEN, false).unwrap();
+    assert_eq!(pool.users[1].lock_end, 110);
+
+    assert_eq!(pool.transfer(1, 0, TOKEN, false), Err(StakingError::RecipientSignatureRequired.into()));
+    assert_eq!(pool.users[0].lock_end, 100);
+
+    pool.transfer(1, 0, TOKEN, true).unwrap();
+    assert_eq!(pool.users[0].lock_end, 110);
+    assert_eq!(pool.users[0].balance, 10 * TOKEN);
+}
+
+#[test]
+fn stake_limits_apply_to_deposits_and_caps_to_compounding() {
+    let mut pool = Pool::new(2, TOKEN, 1_000);
+    pool.state.min_stake_amount = TOKEN;
+    pool.state.max_stake_per_user = 10 * TOKEN;
+    pool.state.max_total_stake = 15 * TOKEN;
+
+    pool.stake(0, 8 * TOKEN);
+    pool.stake(1, 5 * TOKEN);
+
+    assert_eq!(Processor::check_stake_limits(&pool.state, &pool.users[0], TOKEN - 1), Err(StakingError::StakeBelowMinimum.into()));
+    assert_eq!(Processor::check_stake_limits(&pool.state, &pool.users[0], 2 * TOKEN), Ok(()));
+    assert_eq!(Processor::check_stake_limits(&pool.state, &pool.users[0], 2 * TOKEN + 1), Err(StakingError::PoolStakeCapExceeded.into()));
+    assert_eq!(Processor::check_stake_limits(&pool.state, &pool.users[1], 2 * TOKEN), Ok(()));
+
+    pool.state.max_total_stake = 0;
+    assert_eq!(Processor::check_stake_limits(&pool.state, &pool.users[0], 2 * TOKEN + 1), Err(StakingError::UserStakeCapExceeded.into()));
+
+    // compounded rewards may be below the minimum but stay within the caps
+    assert_eq!(Processor::check_stake_caps(&pool.state, &pool.users[0], 1), Ok(()));
+    assert_eq!(Processor::check_stake_caps(&pool.state, &pool.users[0], 2 * TOKEN + 1), Err(StakingError::UserStakeCapExceeded.into()));
+}
+
+/// Pool with a 100 second unbonding period and a flat 10% penalty during the first 1_000 seconds of a stake
+fn unbonding_pool() -> Pool {
+    let mut pool = Pool::new(2, TOKEN, 10_000);
+    pool.state.unbonding_period = 100;
+    pool.state.penalty = PenaltyConfig {
+        period: 1_000,
+        penalty_bps: 1_000,
+        linear_decay: false,
+        destination: PenaltyDestination::Treasury(Pubkey::new_unique()),
+    };
+    pool
+}
+
+#[test]
+fn unbonding_entries_mature_separately_and_withhold_their_penalty() {
+    let mut pool = unbonding_pool();
+
+    pool.stake(0, 10 * TOKEN);
+    pool.advance(500);
+    pool.settle(0);
+    let entry = Processor::request_unbonding(&mut pool.state, &mut pool.users[0], 4 * TOKEN, pool.now).unwrap();
+    assert_eq!(entry, UnbondingEntry { amount: 4 * TOKEN, maturity: 600, penalty: 400_000_000 });
+    assert_eq!(pool.users[0].balance, 6 * TOKEN);
+    assert_eq!(pool.state.total_supply, 6 * TOKEN);
+    assert_eq!(pool.state.total_unbonding, 4 * TOKEN);
+
+    pool.advance(1_000);
+    pool.settle(0);
+    Processor::request_unbonding(&mut pool.state, &mut pool.users[0], 6 * TOKEN, pool.now).unwrap();
+    assert_eq!(
+        Processor::request_unbonding(&mut pool.state, &mut pool.users[0], 1, pool.now),
+        Err(StakingError::InsufficientStake.into()));
+
+    // only the first entry has matured, and it keeps the penalty it was requested with
+    assert_eq!(Processor::take_matured_unbonding(&mut pool.state, &mut pool.users[0], pool.now).unwrap(), (4 * TOKEN, 400_000_000));
+    assert_eq!(pool.users[0].unbonding, vec![UnbondingEntry { amount: 6 * TOKEN, maturity: 1_600, penalty: 0 }]);
+    assert_eq!(pool.state.total_unbonding, 6 * TOKEN);
+    assert_eq!(Processor::take_matured_unbonding(&mut pool.state, &mut pool.users[0], pool.now).unwrap(), (0, 0));
+
+    pool.advance(100);
+    assert_eq!(Processor::take_matured_unbonding(&mut pool.state, &mut pool.users[0], pool.now).unwrap(), (6 * TOKEN, 0));
+    assert!(pool.users[0].unbonding.is_empty());
+    assert_eq!(pool.state.total_unbonding, 0);
+}
+
+#[test]
+fn cancelled_unbonding_is_staked_again_within_the_caps() {
+    let mut pool = unbonding_pool();
+
+    pool.stake(0, 10 * TOKEN);
+    pool.settle(0);
+    Processor::request_unbonding(&mut pool.state, &mut pool.users[0], 4 * TOKEN, pool.now).unwrap();
+    pool.stake(1, 5 * TOKEN);
+
+    assert_eq!(
+        Processor::cancel_unbonding_entry(&mut pool.state, &mut pool.users[0], 1, pool.now),
+        Err(StakingError::InvalidUnbondingIndex.into()));
+
+    pool.state.max_total_stake = 14 * TOKEN;
+    assert_eq!(
+        Processor::cancel_unbonding_entry(&mut pool.state, &mut pool.users[0], 0, pool.now),
+        Err(StakingError::PoolStakeCapExceeded.into()));
+    assert_eq!(pool.users[0].unbonding.len(), 1);
+
+    pool.state.max_total_stake = 15 * TOKEN;
+    let entry = Processor::cancel_unbonding_entry(&mut pool.state, &mut pool.users[0], 0, pool.now).unwrap();
+    assert_eq!(entry.penalty, 400_000_000);
+    assert_eq!(pool.users[0].balance, 10 * TOKEN);
+    assert!(pool.users[0].unbonding.is_empty());
+    assert_eq!(pool.state.total_supply, 15 * TOKEN);
+    assert_eq!(pool.state.total_unbonding, 0);
+}
+
+#[test]
+fn full_unbonding_slots_reject_requests_but_merge_emergency_withdrawals() {
+    let mut pool = unbonding_pool();
+
+    pool.stake(0, 20 * TOKEN);
+    pool.settle(0);
+    for _ in 0..UserStakingState::MAX_UNBONDING_ENTRIES {
+        Processor::request_unbonding(&mut pool.state, &mut pool.users[0], TOKEN, pool.now).unwrap();
+        pool.advance(10);
+    }
+    assert_eq!(
+        Processor::request_unbonding(&mut pool.state, &mut pool.users[0], TOKEN, pool.now),
+        Err(StakingError::TooManyUnbondingEntries.into()));
+
+    // the remaining stake joins the last entry, which now matures with it
+    Processor::unbond_balance(&mut pool.state, &mut pool.users[0], pool.now).unwrap();
+    let last = pool.users[0].unbonding.last().unwrap();
+    assert_eq!(pool.users[0].unbonding.len(), UserStakingState::MAX_UNBONDING_ENTRIES);
+    assert_eq!(*last, UnbondingEntry { amount: 13 * TOKEN, maturity: 180, penalty: 1_300_000_000 });
+    assert_eq!(pool.users[0].balance, 0);
+    assert_eq!(pool.state.total_supply, 0);
+    assert_eq!(pool.state.total_unbonding, 20 * TOKEN);
+}
+
+#[test]
+fn emergency_withdraw_without_unbonding_period_matures_at_once() {
+    let mut pool = unbonding_pool();
+    pool.state.unbonding_period = 0;
+
+    pool.stake(0, 10 * TOKEN);
+    pool.advance(1_000);
+    pool.settle(0);
+    Processor::unbond_balance(&mut pool.state, &mut pool.users[0], pool.now).unwrap();
+    assert_eq!(Processor::take_matured_unbonding(&mut pool.state, &mut pool.users[0], pool.now).unwrap(), (10 * TOKEN, 0));
+    assert!(pool.users[0].unbonding.is_empty());
+    assert_eq!(pool.state.total_unbonding, 0);
+
+    // an empty position has nothing to unbond
+    Processor::unbond_balance(&mut pool.state, &mut pool.users[0], pool.now).unwrap();
+    assert!(pool.users[0].unbonding.is_empty());
+}
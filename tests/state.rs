@@ -0,0 +1,93 @@
+use program2::state::{GlobalConfig, LockTier, PenaltyConfig, PenaltyDestination, RewardInfo, StakingState, UnbondingEntry, UserRewardInfo, UserStakingState};
+use solana_program::pubkey::Pubkey;
+
+#[test]
+fn staking_state_roundtrips_through_account_sized_buffer() {
+    let mut data = vec![0; StakingState::LEN];
+    let state = StakingState {
+        is_initialized: true,
+        admin: Pubkey::new_unique(),
+        pending_admin: None,
+        creator: Pubkey::new_unique(),
+        open_positions: u64::MAX,
+        total_unbonding: u64::MAX,
+        generation: u64::MAX,
+        rewards: vec![RewardInfo {
+            mint: Pubkey::new_unique(),
+            escrow: Pubkey::new_unique(),
+            reward_rate: u64::MAX,
+            reward_per_token_stored: u128::MAX,
+            reward_dust: u128::MAX,
+            last_update_timestamp: i64::MAX,
+            period_finish: i64::MAX,
+            owed_rewards: u64::MAX,
+            total_funded: u64::MAX,
+            unscheduled_funding: u64::MAX,
+        }; StakingState::MAX_REWARD_TOKENS],
+        lock_tiers: vec![LockTier {
+            duration: u64::MAX,
+            multiplier_bps: u16::MAX,
+        }; StakingState::MAX_LOCK_TIERS],
+        unbonding_period: u64::MAX,
+        penalty: PenaltyConfig {
+            period: u64::MAX,
+            penalty_bps: u16::MAX,
+            linear_decay: true,
+            destination: PenaltyDestination::Treasury(Pubkey::new_unique()),
+        },
+        receipt_mint: Some(Pubkey::new_unique()),
+        ..StakingState::default()
+    };
+
+    state.pack(&mut data).unwrap();
+    assert_eq!(StakingState::unpack(&mut data).unwrap(), state);
+
+    let mut proposed = state;
+    proposed.pending_admin = Some(Pubkey::new_unique());
+
+    proposed.pack(&mut data).unwrap();
+    assert_eq!(StakingState::unpack(&mut data).unwrap(), proposed);
+}
+
+#[test]
+fn user_state_fits_account_len() {
+    let mut data = vec![0; UserStakingState::LEN];
+    let user_state = UserStakingState {
+        balance: u64::MAX,
+        effective_balance: u64::MAX,
+        multiplier_bps: u16::MAX,
+        lock_end: i64::MAX,
+        last_stake_timestamp: i64::MAX,
+        last_checkpoint_timestamp: i64::MAX,
+        rewards: vec![UserRewardInfo {
+            reward_per_token_paid: u128::MAX,
+            rewards: u64::MAX,
+            rewards_dust: u128::MAX,
+        }; StakingState::MAX_REWARD_TOKENS],
+        unbonding: vec![UnbondingEntry {
+            amount: u64::MAX,
+            maturity: i64::MAX,
+            penalty: u64::MAX,
+        }; UserStakingState::MAX_UNBONDING_ENTRIES],
+        claim_delegate: Some(Pubkey::new_unique()),
+        reward_destination: Some(Pubkey::new_unique()),
+        generation: u64::MAX,
+    };
+
+    user_state.pack(&mut data).unwrap();
+    assert_eq!(UserStakingState::unpack(&mut data).unwrap(), user_state);
+}
+
+#[test]
+fn global_config_fits_account_len() {
+    let mut data = vec![0; GlobalConfig::LEN];
+    let global_config = GlobalConfig {
+        is_initialized: true,
+        authority: Pubkey::new_unique(),
+        fee_bps: u16::MAX,
+        fee_recipient: Pubkey::new_unique(),
+    };
+
+    global_config.pack(&mut data).unwrap();
+    assert_eq!(GlobalConfig::unpack(&mut data).unwrap(), global_config);
+}